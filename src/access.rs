@@ -0,0 +1,252 @@
+/* src/access.rs */
+
+use crate::key::LimitKey;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IPv4 or IPv6 network in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpCidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpCidr {
+    /// Returns `None` if `prefix` is longer than the address.
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Self> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix > max {
+            return None;
+        }
+        Some(Self {
+            network: mask(addr, prefix),
+            prefix,
+        })
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// IPv4-mapped IPv6 addresses, as seen on dual-stack listeners, are
+    /// matched as the IPv4 addresses they carry.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(_), addr @ IpAddr::V4(_)) | (IpAddr::V6(_), addr @ IpAddr::V6(_)) => {
+                mask(addr, self.prefix) == self.network
+            }
+            // A network written in mapped form, e.g. `::ffff:0:0/96`.
+            (IpAddr::V6(_), IpAddr::V4(_)) => mask(*addr, self.prefix) == self.network,
+            _ => false,
+        }
+    }
+}
+
+/// Zero every bit of `addr` after the first `prefix` bits.
+pub(crate) fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4);
+            let masked = bits & u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            IpAddr::V4(masked.into())
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::from(v6);
+            let masked = bits & u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            IpAddr::V6(masked.into())
+        }
+    }
+}
+
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Error returned when parsing an [`IpCidr`] fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CidrParseError(String);
+
+impl fmt::Display for CidrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid CIDR block: {}", self.0)
+    }
+}
+
+impl std::error::Error for CidrParseError {}
+
+impl FromStr for IpCidr {
+    type Err = CidrParseError;
+
+    /// Accepts `addr/prefix`, or a bare address as a single-host network.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || CidrParseError(s.to_string());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => {
+                let addr: IpAddr = addr.parse().map_err(|_| err())?;
+                (addr, prefix.parse().map_err(|_| err())?)
+            }
+            None => {
+                let addr: IpAddr = s.parse().map_err(|_| err())?;
+                let prefix = if addr.is_ipv4() { 32 } else { 128 };
+                (addr, prefix)
+            }
+        };
+        IpCidr::new(addr, prefix).ok_or_else(err)
+    }
+}
+
+/// Groups IP identities into networks so a rule counts a whole subnet as one client.
+///
+/// Without it an attacker holding an IPv6 /64 gets 2^64 independent buckets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpAggregation {
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+}
+
+impl IpAggregation {
+    pub fn new(ipv4_prefix: u8, ipv6_prefix: u8) -> Self {
+        Self {
+            ipv4_prefix: ipv4_prefix.min(32),
+            ipv6_prefix: ipv6_prefix.min(128),
+        }
+    }
+
+    /// Map `who` to its network, e.g. `2001:db8::1` to `2001:db8::/64`.
+    /// Identities that are not IP addresses are returned unchanged.
+    pub fn normalize<'a>(&self, who: &'a str) -> Cow<'a, str> {
+        match who.parse::<IpAddr>() {
+            Ok(addr) => Cow::Owned(self.network(addr).to_string()),
+            Err(_) => Cow::Borrowed(who),
+        }
+    }
+
+    /// The network `addr` belongs to. IPv4-mapped IPv6 addresses, as seen on
+    /// dual-stack listeners, are grouped as the IPv4 addresses they carry.
+    pub(crate) fn network(&self, addr: IpAddr) -> IpCidr {
+        let addr = addr.to_canonical();
+        let prefix = if addr.is_ipv4() {
+            self.ipv4_prefix
+        } else {
+            self.ipv6_prefix
+        };
+        IpCidr {
+            network: mask(addr, prefix),
+            prefix,
+        }
+    }
+}
+
+impl Default for IpAggregation {
+    /// `/24` for IPv4 and `/64` for IPv6.
+    fn default() -> Self {
+        Self::new(24, 64)
+    }
+}
+
+/// Exact identities and IP networks that a `who` key is matched against.
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    pub identities: HashSet<String>,
+    pub networks: Vec<IpCidr>,
+}
+
+impl AccessList {
+    pub fn is_empty(&self) -> bool {
+        self.identities.is_empty() && self.networks.is_empty()
+    }
+
+    /// `who` matches if it is listed verbatim or is an IP inside a listed network.
+    pub fn matches<K: ?Sized + LimitKey>(&self, who: &K) -> bool {
+        if !self.identities.is_empty() && self.identities.contains(who.name().as_ref()) {
+            return true;
+        }
+        if self.networks.is_empty() {
+            return false;
+        }
+        match who.ip() {
+            Some(addr) => self.networks.iter().any(|net| net.contains(&addr)),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr_parse_and_contains() {
+        let v4: IpCidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(v4.to_string(), "10.0.0.0/8");
+        assert!(v4.contains(&"10.255.0.1".parse().unwrap()));
+        assert!(!v4.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(
+            v4.contains(&"::ffff:10.0.0.1".parse().unwrap()),
+            "IPv4-mapped addresses match IPv4 networks"
+        );
+        assert!(!v4.contains(&"::ffff:11.0.0.1".parse().unwrap()));
+        let mapped: IpCidr = "::ffff:0:0/96".parse().unwrap();
+        assert!(mapped.contains(&"::ffff:10.0.0.1".parse().unwrap()));
+
+        let v6: IpCidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(&"2001:db8:ffff::1".parse().unwrap()));
+        assert!(!v6.contains(&"2001:db9::1".parse().unwrap()));
+
+        let host: IpCidr = "192.168.0.7".parse().unwrap();
+        assert_eq!(host.prefix(), 32);
+        let any: IpCidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&"8.8.8.8".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("not-an-ip/8".parse::<IpCidr>().is_err());
+    }
+
+    #[test]
+    fn test_ip_aggregation() {
+        let aggregation = IpAggregation::default();
+        assert_eq!(aggregation.normalize("192.0.2.77"), "192.0.2.0/24");
+        assert_eq!(
+            aggregation.normalize("2001:db8:1:2:aaaa:bbbb:cccc:dddd"),
+            "2001:db8:1:2::/64"
+        );
+        assert_eq!(
+            aggregation.normalize("::ffff:192.0.2.1"),
+            "192.0.2.0/24",
+            "IPv4-mapped addresses aggregate as IPv4"
+        );
+        assert_eq!(
+            aggregation.normalize("::ffff:198.51.100.7"),
+            "198.51.100.0/24"
+        );
+        assert_eq!(aggregation.normalize("api-key-123"), "api-key-123");
+        assert_eq!(IpAggregation::new(40, 200), IpAggregation::new(32, 128));
+    }
+
+    #[test]
+    fn test_access_list_matches() {
+        let mut list = AccessList::default();
+        assert!(!list.matches("10.0.0.1"));
+
+        list.identities.insert("health-checker".to_string());
+        list.networks.push("10.0.0.0/8".parse().unwrap());
+        assert!(list.matches("health-checker"));
+        assert!(list.matches("10.20.30.40"));
+        assert!(!list.matches("192.168.0.1"));
+        assert!(!list.matches("someone-else"));
+        assert!(list.matches("::ffff:10.66.1.1"));
+        assert!(!list.matches("::ffff:192.168.0.1"));
+    }
+}
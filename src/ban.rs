@@ -0,0 +1,90 @@
+/* src/ban.rs */
+
+use crate::types::{Duration, current_timestamp};
+use std::collections::HashMap;
+
+/// Automatically ban an identity that keeps hitting its limits.
+#[derive(Debug, Clone)]
+pub struct BanPolicy {
+    /// Number of rate-limited requests that triggers a ban...
+    pub max_denials: u32,
+    /// ...when they all fall within this window.
+    pub window: Duration,
+    /// How long the resulting ban lasts.
+    pub ban_for: Duration,
+}
+
+impl BanPolicy {
+    pub fn new(max_denials: u32, window: Duration, ban_for: Duration) -> Self {
+        Self {
+            max_denials,
+            window,
+            ban_for,
+        }
+    }
+}
+
+/// Active bans and the recent denials that feed the automatic policy.
+#[derive(Debug, Default)]
+pub(crate) struct BanList {
+    bans: HashMap<String, u64>,
+    denials: HashMap<String, Vec<u64>>,
+}
+
+impl BanList {
+    /// Ban `who` for `duration`, returning when the ban ends.
+    pub fn ban(&mut self, who: &str, duration: Duration) -> u64 {
+        let until = current_timestamp() + duration.as_seconds();
+        self.bans.insert(who.to_string(), until);
+        self.denials.remove(who);
+        until
+    }
+
+    pub fn unban(&mut self, who: &str) -> bool {
+        self.denials.remove(who);
+        self.bans.remove(who).is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bans.is_empty()
+    }
+
+    /// When the active ban on `who` ends, if there is one.
+    pub fn banned_until(&self, who: &str) -> Option<u64> {
+        self.bans
+            .get(who)
+            .copied()
+            .filter(|&until| until > current_timestamp())
+    }
+
+    /// Count a rate-limited request, banning `who` once the policy is met.
+    pub fn record_denial(&mut self, who: &str, policy: &BanPolicy) -> Option<u64> {
+        let now = current_timestamp();
+        let cutoff = now.saturating_sub(policy.window.as_seconds());
+        let denials = self.denials.entry(who.to_string()).or_default();
+        denials.retain(|&t| t > cutoff);
+        denials.push(now);
+
+        if denials.len() as u32 >= policy.max_denials {
+            Some(self.ban(who, policy.ban_for))
+        } else {
+            None
+        }
+    }
+
+    /// Drop expired bans and denials older than `denial_window` seconds.
+    /// Returns how many bans expired.
+    pub fn cleanup(&mut self, denial_window: u64) -> usize {
+        let now = current_timestamp();
+        let before = self.bans.len();
+        self.bans.retain(|_who, &mut until| until > now);
+
+        let cutoff = now.saturating_sub(denial_window);
+        self.denials.retain(|_who, denials| {
+            denials.retain(|&t| t > cutoff);
+            !denials.is_empty()
+        });
+
+        before - self.bans.len()
+    }
+}
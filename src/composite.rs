@@ -0,0 +1,86 @@
+/* src/composite.rs */
+
+use crate::types::{RuleConfig, ScopeRef};
+use std::fmt;
+
+/// A request identity made of named dimensions, e.g. API key, IP, tenant and route.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompositeKey {
+    dimensions: Vec<(String, String)>,
+}
+
+impl CompositeKey {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set a dimension, replacing any previous value with the same name.
+    pub fn with(mut self, name: &str, value: &str) -> Self {
+        match self.dimensions.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.dimensions.push((name.to_string(), value.to_string())),
+        }
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.dimensions
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &str> {
+        self.dimensions.iter().map(|(_, v)| v.as_str())
+    }
+
+    /// The record key for `rule`, or `None` if a dimension it needs is missing.
+    ///
+    /// Values are length-prefixed so no value can alias another combination.
+    pub(crate) fn bucket(&self, rule: &DimensionRule) -> Option<String> {
+        let mut bucket = String::new();
+        for name in &rule.dimensions {
+            let value = rule.rule.normalize_key(self.get(name)?);
+            if !bucket.is_empty() {
+                bucket.push(',');
+            }
+            bucket.push_str(&format!("{}={}:{}", name, value.len(), value));
+        }
+        Some(bucket)
+    }
+}
+
+impl fmt::Display for CompositeKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.dimensions.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// A rule counted per distinct combination of the named dimensions.
+#[derive(Debug, Clone)]
+pub struct DimensionRule {
+    pub name: String,
+    pub dimensions: Vec<String>,
+    pub rule: RuleConfig,
+}
+
+impl DimensionRule {
+    pub fn new(name: &str, dimensions: &[&str], rule: RuleConfig) -> Self {
+        Self {
+            name: name.to_string(),
+            dimensions: dimensions.iter().map(|d| d.to_string()).collect(),
+            rule,
+        }
+    }
+
+    /// The scope its records are stored under, kept apart from real routes.
+    pub(crate) fn scope(&self) -> ScopeRef<'_> {
+        ScopeRef::Dimension(&self.name)
+    }
+}
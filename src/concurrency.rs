@@ -0,0 +1,81 @@
+/* src/concurrency.rs */
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+type InFlightMap = HashMap<(String, String), u32>;
+type SharedCounts = Arc<Mutex<InFlightMap>>;
+
+/// Requests currently in flight per `(who, route)`.
+///
+/// Uses a std mutex because permits release their slot from `Drop`, which
+/// cannot await.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct InFlight {
+    counts: SharedCounts,
+}
+
+impl InFlight {
+    pub fn current(&self, who: &str, route: &str) -> u32 {
+        let counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        counts
+            .get(&(who.to_string(), route.to_string()))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Take a slot if fewer than `max` are in use.
+    pub fn try_acquire(&self, who: &str, route: &str, max: u32) -> Option<Permit> {
+        let key = (who.to_string(), route.to_string());
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let count = counts.entry(key.clone()).or_insert(0);
+        if *count >= max {
+            if *count == 0 {
+                counts.remove(&key);
+            }
+            return None;
+        }
+        *count += 1;
+        Some(Permit {
+            slot: Some(Slot {
+                counts: self.counts.clone(),
+                key,
+            }),
+        })
+    }
+}
+
+/// Proof that a request was admitted. Dropping it frees its concurrency slot.
+#[derive(Debug)]
+#[must_use = "dropping the permit immediately releases its concurrency slot"]
+pub struct Permit {
+    slot: Option<Slot>,
+}
+
+#[derive(Debug)]
+struct Slot {
+    counts: SharedCounts,
+    key: (String, String),
+}
+
+impl Permit {
+    /// A permit for a route without a concurrency limit.
+    pub(crate) fn unlimited() -> Self {
+        Self { slot: None }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let Some(slot) = self.slot.take() else {
+            return;
+        };
+        let mut counts = slot.counts.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = counts.get_mut(&slot.key) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                counts.remove(&slot.key);
+            }
+        }
+    }
+}
@@ -1,15 +1,102 @@
 /* src/config.rs */
 
+use crate::access::{AccessList, IpCidr};
+use crate::ban::BanPolicy;
+use crate::composite::DimensionRule;
+use crate::key::LimitKey;
+use crate::tier::{TierConfig, TierResolver};
 use crate::types::{Duration, RuleConfig};
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// How a route's own rule combines with the global default rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RouteMode {
+    /// Check the global default rule and the route rule (the default rule when
+    /// the route has none).
+    #[default]
+    Inherit,
+    /// Only check the route rule (the default rule when the route has none).
+    Replace,
+    /// Never rate limit the route.
+    Exempt,
+    /// Reject every request to the route.
+    Deny,
+}
+
+/// What override mode does with routes that have no route rule.
+#[derive(Debug, Clone, Default)]
+pub enum UnmatchedRoute {
+    /// Allow the request without limits.
+    #[default]
+    Allow,
+    /// Reject the request with `Decision::RouteDenied`.
+    Deny,
+    /// Limit the route with this rule instead.
+    CatchAll(RuleConfig),
+}
+
+type ReportFn = dyn Fn(&str) + Send + Sync;
+
+/// Called with every checked route that has no route rule or route mode.
+#[derive(Clone)]
+pub struct UnmatchedRouteHook(Arc<ReportFn>);
+
+impl UnmatchedRouteHook {
+    pub fn new(report: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(report))
+    }
+
+    pub fn report(&self, route: &str) {
+        (self.0)(route)
+    }
+}
+
+impl fmt::Debug for UnmatchedRouteHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("UnmatchedRouteHook(..)")
+    }
+}
 
 /// Configuration for the rate limiter
 #[derive(Debug, Clone)]
 pub struct LimiterConfig {
     pub default_rule: RuleConfig,
     pub route_rules: HashMap<String, RuleConfig>,
+    /// Composition per route. Routes not listed use `Inherit` if they have a
+    /// route rule and `unknown_route_mode` otherwise.
+    pub route_modes: HashMap<String, RouteMode>,
+    /// Composition for routes with neither a route rule nor a route mode.
+    pub unknown_route_mode: RouteMode,
+    /// How override mode handles routes without a route rule.
+    pub unmatched_override: UnmatchedRoute,
+    pub unmatched_route_hook: Option<UnmatchedRouteHook>,
+    /// Rules evaluated by `decide_composite`, each keyed by a subset of dimensions.
+    pub dimension_rules: Vec<DimensionRule>,
+    /// Composite key dimension matched against the allowlist, e.g. `"ip"`.
+    /// Without one the allowlist does not apply to `decide_composite`.
+    pub allowlist_dimension: Option<String>,
+    /// Composite key dimension that `ban_policy` counts denials against.
+    /// Without one, composite checks do not lead to automatic bans.
+    pub ban_dimension: Option<String>,
+    /// Maximum requests in flight per identity on a route, enforced by `acquire_permit`.
+    pub concurrency_limits: HashMap<String, u32>,
+    /// Named plans that replace the default and route rules for their members.
+    pub tiers: HashMap<String, TierConfig>,
+    /// Static identity to tier name assignments, checked before `tier_resolver`.
+    pub tier_assignments: HashMap<String, String>,
+    pub tier_resolver: Option<TierResolver>,
     pub max_memory: usize,
     pub gc_interval: u64,
+    /// Most `until_ready` callers allowed to queue for the same `(who, route)`.
+    pub max_queue_len: usize,
+    /// Identities that bypass every rule.
+    pub allowlist: AccessList,
+    /// Identities that are always rejected. Takes precedence over the allowlist.
+    pub denylist: AccessList,
+    /// Ban identities that keep getting rate limited.
+    pub ban_policy: Option<BanPolicy>,
 }
 
 impl LimiterConfig {
@@ -17,8 +104,23 @@ impl LimiterConfig {
         Self {
             default_rule,
             route_rules: HashMap::new(),
+            route_modes: HashMap::new(),
+            unknown_route_mode: RouteMode::Inherit,
+            unmatched_override: UnmatchedRoute::Allow,
+            unmatched_route_hook: None,
+            dimension_rules: Vec::new(),
+            allowlist_dimension: None,
+            ban_dimension: None,
+            concurrency_limits: HashMap::new(),
+            tiers: HashMap::new(),
+            tier_assignments: HashMap::new(),
+            tier_resolver: None,
             max_memory: 64 * 1024 * 1024, // 64MB default
             gc_interval: 10,              // 10 seconds default
+            max_queue_len: 1024,
+            allowlist: AccessList::default(),
+            denylist: AccessList::default(),
+            ban_policy: None,
         }
     }
 
@@ -27,6 +129,126 @@ impl LimiterConfig {
         self
     }
 
+    pub fn with_route_mode(mut self, route: &str, mode: RouteMode) -> Self {
+        self.route_modes.insert(route.to_string(), mode);
+        self
+    }
+
+    /// Composition for routes without a rule, e.g. `RouteMode::Deny` so new
+    /// endpoints are rejected until someone configures them.
+    pub fn with_unknown_route_mode(mut self, mode: RouteMode) -> Self {
+        self.unknown_route_mode = mode;
+        self
+    }
+
+    /// Make override mode strict: deny unmatched routes or send them to a
+    /// catch-all rule instead of allowing them.
+    pub fn with_unmatched_override(mut self, unmatched: UnmatchedRoute) -> Self {
+        self.unmatched_override = unmatched;
+        self
+    }
+
+    /// Report routes that fall through to unmatched handling, e.g. to log
+    /// endpoints that still need a rule. Called on every such check.
+    pub fn on_unmatched_route(mut self, report: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.unmatched_route_hook = Some(UnmatchedRouteHook::new(report));
+        self
+    }
+
+    /// The composition for `route`, given whether a route rule matched it
+    /// (tier and resolved rules included).
+    pub(crate) fn route_mode(&self, route: &str, has_rule: bool) -> RouteMode {
+        match self.route_modes.get(route) {
+            Some(&mode) => mode,
+            None if has_rule => RouteMode::Inherit,
+            None => self.unknown_route_mode,
+        }
+    }
+
+    /// Add a rule counted per distinct combination of `dimensions`, e.g.
+    /// `&["api_key", "route"]` for "per API key per route".
+    pub fn add_dimension_rule(mut self, name: &str, dimensions: &[&str], rule: RuleConfig) -> Self {
+        self.dimension_rules
+            .push(DimensionRule::new(name, dimensions, rule));
+        self
+    }
+
+    /// Match the allowlist against this dimension of composite keys. Pick one
+    /// the server fills in, such as the peer IP, rather than a client-supplied value.
+    pub fn with_allowlist_dimension(mut self, dimension: &str) -> Self {
+        self.allowlist_dimension = Some(dimension.to_string());
+        self
+    }
+
+    /// Count composite denials against this dimension for the ban policy,
+    /// e.g. `"ip"`. As with the allowlist, pick one the server fills in.
+    pub fn with_ban_dimension(mut self, dimension: &str) -> Self {
+        self.ban_dimension = Some(dimension.to_string());
+        self
+    }
+
+    /// Allow at most `max_in_flight` simultaneous requests per identity on `route`.
+    pub fn add_concurrency_limit(mut self, route: &str, max_in_flight: u32) -> Self {
+        self.concurrency_limits
+            .insert(route.to_string(), max_in_flight);
+        self
+    }
+
+    pub fn add_tier(mut self, name: &str, tier: TierConfig) -> Self {
+        self.tiers.insert(name.to_string(), tier);
+        self
+    }
+
+    pub fn assign_tier(mut self, who: &str, tier: &str) -> Self {
+        self.tier_assignments
+            .insert(who.to_string(), tier.to_string());
+        self
+    }
+
+    /// Look up tiers for identities without a static assignment.
+    pub fn with_tier_resolver(
+        mut self,
+        resolve: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.tier_resolver = Some(TierResolver::new(resolve));
+        self
+    }
+
+    /// The tier `who` belongs to, if it is mapped to one that exists.
+    pub fn tier_for(&self, who: &str) -> Option<&TierConfig> {
+        if let Some(name) = self.tier_assignments.get(who) {
+            return self.tiers.get(name);
+        }
+        let name = self.tier_resolver.as_ref()?.resolve(who)?;
+        self.tiers.get(&name)
+    }
+
+    /// The default rule and, if there is one, the route rule that apply to `who`.
+    ///
+    /// Tier members use their tier's default rule. Route rules come from the
+    /// tier first and fall back to the base route rules, so limits such as a
+    /// login rule keep applying to every tier unless a tier overrides them.
+    pub fn rules_for<K: ?Sized + LimitKey>(
+        &self,
+        who: &K,
+        route: &str,
+    ) -> (&RuleConfig, Option<&RuleConfig>) {
+        let tier = if self.tiers.is_empty() {
+            None
+        } else {
+            self.tier_for(&who.name())
+        };
+        match tier {
+            Some(tier) => (
+                &tier.default_rule,
+                tier.route_rules
+                    .get(route)
+                    .or_else(|| self.route_rules.get(route)),
+            ),
+            None => (&self.default_rule, self.route_rules.get(route)),
+        }
+    }
+
     pub fn with_max_memory(mut self, max_memory: usize) -> Self {
         self.max_memory = max_memory;
         self
@@ -37,10 +259,55 @@ impl LimiterConfig {
         self
     }
 
+    pub fn allow_identity(mut self, who: &str) -> Self {
+        self.allowlist.identities.insert(who.to_string());
+        self
+    }
+
+    pub fn allow_network(mut self, network: IpCidr) -> Self {
+        self.allowlist.networks.push(network);
+        self
+    }
+
+    pub fn deny_identity(mut self, who: &str) -> Self {
+        self.denylist.identities.insert(who.to_string());
+        self
+    }
+
+    pub fn deny_network(mut self, network: IpCidr) -> Self {
+        self.denylist.networks.push(network);
+        self
+    }
+
+    pub fn with_ban_policy(mut self, policy: BanPolicy) -> Self {
+        self.ban_policy = Some(policy);
+        self
+    }
+
+    pub fn with_max_queue_len(mut self, max_queue_len: usize) -> Self {
+        self.max_queue_len = max_queue_len;
+        self
+    }
+
     pub fn max_interval(&self) -> Duration {
         let mut max = self.default_rule.interval;
 
-        for rule in self.route_rules.values() {
+        let dimension_rules = self.dimension_rules.iter().map(|d| &d.rule);
+        let catch_all = match &self.unmatched_override {
+            UnmatchedRoute::CatchAll(rule) => Some(rule),
+            _ => None,
+        };
+        let tier_rules = self
+            .tiers
+            .values()
+            .flat_map(|t| std::iter::once(&t.default_rule).chain(t.route_rules.values()));
+        for rule in self
+            .route_rules
+            .values()
+            .chain(dimension_rules)
+            .chain(tier_rules)
+            .chain(catch_all)
+        {
             if rule.interval > max {
                 max = rule.interval;
             }
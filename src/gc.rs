@@ -1,23 +1,36 @@
 /* src/gc.rs */
 
+use crate::ban::BanList;
 use crate::config::LimiterConfig;
-use crate::types::RequestRecord;
-use std::collections::HashMap;
+use crate::key::LimitKey;
+use crate::stats::{GcMode, GcReport};
+use crate::store::Store;
+use crate::telemetry;
+use crate::types::current_timestamp;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tokio::time::{Duration as TokioDuration, interval};
 
-pub struct GarbageCollector {
-    records: Arc<RwLock<HashMap<String, HashMap<String, RequestRecord>>>>,
+pub struct GarbageCollector<K: ?Sized + LimitKey> {
+    records: Arc<RwLock<Store<K>>>,
     config: LimiterConfig,
+    last_report: Arc<RwLock<Option<GcReport>>>,
+    bans: Arc<RwLock<BanList>>,
 }
 
-impl GarbageCollector {
+impl<K: ?Sized + LimitKey> GarbageCollector<K> {
     pub fn new(
-        records: Arc<RwLock<HashMap<String, HashMap<String, RequestRecord>>>>,
+        records: Arc<RwLock<Store<K>>>,
         config: LimiterConfig,
+        last_report: Arc<RwLock<Option<GcReport>>>,
+        bans: Arc<RwLock<BanList>>,
     ) -> Self {
-        Self { records, config }
+        Self {
+            records,
+            config,
+            last_report,
+            bans,
+        }
     }
 
     pub async fn start(self) {
@@ -31,90 +44,132 @@ impl GarbageCollector {
 
     async fn collect_garbage(&self) {
         let mut records = self.records.write().await;
-        let current_memory = self.estimate_memory_usage(&records);
+        let current_memory = records.memory_usage();
 
-        if current_memory > self.config.max_memory {
-            self.aggressive_cleanup(&mut records).await;
+        let (mode, expired, evicted) = if current_memory > self.config.max_memory {
+            let (expired, evicted) = self.aggressive_cleanup(&mut records).await;
+            (GcMode::Aggressive, expired, evicted)
         } else {
-            self.routine_cleanup(&mut records).await;
-        }
+            (GcMode::Routine, self.routine_cleanup(&mut records).await, 0)
+        };
+        let removed = expired + evicted;
+
+        let report = GcReport {
+            finished_at: current_timestamp(),
+            mode,
+            removed,
+            memory_before: current_memory,
+            memory_after: records.memory_usage(),
+        };
+        drop(records);
+
+        telemetry::record_gc_run(
+            mode.as_str(),
+            removed,
+            evicted,
+            report.memory_before,
+            report.memory_after,
+        );
+        *self.last_report.write().await = Some(report);
+
+        let denial_window = self
+            .config
+            .ban_policy
+            .as_ref()
+            .map_or(0, |policy| policy.window.as_seconds());
+        self.bans.write().await.cleanup(denial_window);
     }
 
-    async fn routine_cleanup(&self, records: &mut HashMap<String, HashMap<String, RequestRecord>>) {
+    /// Drop records idle for longer than any rule window. Returns how many were removed.
+    async fn routine_cleanup(&self, records: &mut Store<K>) -> usize {
         let max_age = self.config.max_interval().as_seconds() + 300; // Add 5 min buffer
-
-        records.retain(|_who, route_records| {
-            route_records.retain(|_route, record| !record.should_cleanup(max_age));
-            !route_records.is_empty()
-        });
+        let removed = records.retain(|_who, _scope, record| !record.should_cleanup(max_age));
+        records.shrink();
+        removed
     }
 
-    async fn aggressive_cleanup(
-        &self,
-        records: &mut HashMap<String, HashMap<String, RequestRecord>>,
-    ) {
-        self.routine_cleanup(records).await;
+    /// Drop idle records, then evict the least recently active ones if that
+    /// was not enough. Returns how many expired and how many were evicted.
+    async fn aggressive_cleanup(&self, records: &mut Store<K>) -> (usize, usize) {
+        let expired = self.routine_cleanup(records).await;
 
-        let current_memory = self.estimate_memory_usage(records);
-        if current_memory > self.config.max_memory {
+        let mut evicted = 0;
+        if records.memory_usage() > self.config.max_memory {
             let target_memory = self.config.max_memory * 80 / 100;
-            self.remove_oldest_entries(records, target_memory).await;
-        }
-    }
-
-    async fn remove_oldest_entries(
-        &self,
-        records: &mut HashMap<String, HashMap<String, RequestRecord>>,
-        target_memory: usize,
-    ) {
-        let mut entries: Vec<(String, String, u64)> = Vec::new();
-
-        for (who, route_records) in records.iter() {
-            for (route, record) in route_records.iter() {
-                let oldest_time = if record.timestamps.is_empty() {
-                    record.window_start
-                } else {
-                    *record.timestamps.first().unwrap_or(&record.window_start)
-                };
-                entries.push((who.clone(), route.clone(), oldest_time));
-            }
+            evicted = records.evict_to(target_memory, None);
         }
 
-        entries.sort_by_key(|&(_, _, timestamp)| timestamp);
-
-        let mut current_memory = self.estimate_memory_usage(records);
-        for (who, route, _) in entries {
-            if current_memory <= target_memory {
-                break;
-            }
+        (expired, evicted)
+    }
+}
 
-            if let Some(route_records) = records.get_mut(&who) {
-                if let Some(removed_record) = route_records.remove(&route) {
-                    current_memory -= route.len() + removed_record.memory_usage();
-                }
-                if route_records.is_empty() {
-                    records.remove(&who);
-                    current_memory -=
-                        who.len() + std::mem::size_of::<HashMap<String, RequestRecord>>();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Duration, Penalty, RequestRecord, RuleConfig, ScopeRef};
+
+    #[tokio::test]
+    async fn test_penalty_streak_survives_gc() {
+        let penalty = Penalty::new(Duration::seconds(1), Duration::hours(1), Duration::days(1));
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(1), 1));
+        let records = Arc::new(RwLock::new(Store::<str>::new(usize::MAX)));
+        let gc = GarbageCollector::new(
+            records.clone(),
+            config,
+            Arc::new(RwLock::new(None)),
+            Arc::new(RwLock::new(BanList::default())),
+        );
+
+        {
+            let mut records = records.write().await;
+            for who in ["offender", "quiet"] {
+                let mut record =
+                    records.get_or_insert(who, ScopeRef::Global, || RequestRecord::new(true));
+                if who == "offender" {
+                    record.register_violation(&penalty);
                 }
+                // Idle for longer than routine cleanup's max age, block served.
+                record.window_start -= 1000;
+                record.last_violation = record.last_violation.saturating_sub(1000);
+                record.blocked_until = 0;
             }
         }
+        gc.collect_garbage().await;
+
+        let mut records = records.write().await;
+        assert!(records.get("quiet", ScopeRef::Global).is_none());
+        let mut record = records
+            .get_mut("offender", ScopeRef::Global)
+            .expect("streak is kept until decay_after");
+        record.register_violation(&penalty);
+        assert_eq!(record.violations, 2);
     }
 
-    fn estimate_memory_usage(
-        &self,
-        records: &HashMap<String, HashMap<String, RequestRecord>>,
-    ) -> usize {
-        let mut total = 0;
-
-        for (who, route_records) in records.iter() {
-            total += who.capacity() + std::mem::size_of::<HashMap<String, RequestRecord>>();
-
-            for (route, record) in route_records.iter() {
-                total += route.capacity() + record.memory_usage();
+    #[tokio::test]
+    async fn test_aggressive_cleanup_counts_expired_and_evicted_apart() {
+        let config =
+            LimiterConfig::new(RuleConfig::new(Duration::seconds(1), 1)).with_max_memory(4 * 1024);
+        let records = Arc::new(RwLock::new(Store::<str>::new(usize::MAX)));
+        let gc = GarbageCollector::new(
+            records.clone(),
+            config,
+            Arc::new(RwLock::new(None)),
+            Arc::new(RwLock::new(BanList::default())),
+        );
+
+        let mut records = records.write().await;
+        for i in 0..200 {
+            let mut record =
+                records.get_or_insert(&format!("client-{i}"), ScopeRef::Global, || {
+                    RequestRecord::new(true)
+                });
+            if i < 3 {
+                record.window_start -= 1000;
             }
         }
-
-        total
+        let (expired, evicted) = gc.aggressive_cleanup(&mut records).await;
+        assert_eq!(expired, 3);
+        assert!(evicted > 0);
+        assert_eq!(expired + evicted, 200 - records.identities().count());
     }
 }
@@ -0,0 +1,78 @@
+/* src/key.rs */
+
+use crate::access::IpAggregation;
+use crate::store::allocation_size;
+use std::borrow::Cow;
+use std::fmt;
+use std::hash::Hash;
+use std::net::IpAddr;
+
+/// An identity the limiter can count requests for.
+///
+/// Records are stored under the owned form of the key, so compact keys such
+/// as `IpAddr` or numeric user IDs avoid formatting and allocating a string
+/// per record. Allowlists, bans, tiers and resolvers still work by name and
+/// only format the key when they are configured.
+pub trait LimitKey:
+    Hash
+    + Eq
+    + fmt::Display
+    + fmt::Debug
+    + Send
+    + Sync
+    + ToOwned<Owned: Hash + Eq + Clone + fmt::Debug + Send + Sync>
+    + 'static
+{
+    /// The textual form matched against identity lists, bans and tiers.
+    fn name(&self) -> Cow<'_, str> {
+        Cow::Owned(self.to_string())
+    }
+
+    /// The IP address this key stands for, matched against CIDR lists.
+    fn ip(&self) -> Option<IpAddr> {
+        None
+    }
+
+    /// The key a rule with IP aggregation counts this one under. Keys that
+    /// are not IP addresses are counted as themselves.
+    fn aggregate(&self, aggregation: &IpAggregation) -> Cow<'_, Self> {
+        let _ = aggregation;
+        Cow::Borrowed(self)
+    }
+
+    /// Heap memory held by the owned key.
+    fn memory_usage(&self) -> usize {
+        0
+    }
+}
+
+impl LimitKey for str {
+    fn name(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self)
+    }
+
+    fn ip(&self) -> Option<IpAddr> {
+        self.parse().ok()
+    }
+
+    fn aggregate(&self, aggregation: &IpAggregation) -> Cow<'_, Self> {
+        aggregation.normalize(self)
+    }
+
+    fn memory_usage(&self) -> usize {
+        allocation_size(self.len())
+    }
+}
+
+impl LimitKey for IpAddr {
+    fn ip(&self) -> Option<IpAddr> {
+        Some(*self)
+    }
+
+    /// The network address, e.g. `2001:db8::1` becomes `2001:db8::`.
+    fn aggregate(&self, aggregation: &IpAggregation) -> Cow<'_, Self> {
+        Cow::Owned(aggregation.network(*self).network())
+    }
+}
+
+impl LimitKey for u64 {}
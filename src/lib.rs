@@ -1,15 +1,39 @@
 /* src/lib.rs */
 
+use std::ops::ControlFlow;
 use std::sync::Arc;
+use std::time::Duration as StdDuration;
 use tokio::sync::{OnceCell, RwLock};
+use tokio::time::Instant;
 
+mod access;
+mod ban;
+mod composite;
+mod concurrency;
 mod config;
 mod gc;
+mod key;
 mod limiter;
+mod queue;
+mod replication;
+mod resolver;
+mod stats;
+mod store;
+mod telemetry;
+mod tier;
 mod types;
 
+pub use access::*;
+pub use ban::BanPolicy;
+pub use composite::*;
+pub use concurrency::Permit;
 pub use config::*;
-use limiter::RateLimiter;
+pub use key::LimitKey;
+pub use limiter::{QuotaToken, RateLimiter};
+pub use replication::*;
+pub use resolver::ResolvedRules;
+pub use stats::*;
+pub use tier::*;
 pub use types::*;
 
 // Global rate limiter instance, initialized once.
@@ -92,45 +116,272 @@ macro_rules! limit_override {
 
 /// Initialize the global rate limiter. Should be called only once.
 pub async fn initialize_limiter(config: LimiterConfig) {
-    let limiter = RateLimiter::new(config).await;
+    install_limiter(RateLimiter::new(config).await);
+}
+
+/// Install an already built limiter as the global instance, e.g. one with replication enabled.
+/// Should be called only once.
+pub fn install_limiter(mut limiter: RateLimiter) {
+    limiter.prefetch_rules();
     if GLOBAL_LIMITER.set(Arc::new(RwLock::new(limiter))).is_err() {
         panic!("Rate limiter has already been initialized.");
     }
 }
 
+fn global_limiter() -> &'static RwLock<RateLimiter> {
+    GLOBAL_LIMITER
+        .get()
+        .expect("Rate limiter not initialized! Call init_rate_limiter! first.")
+}
+
+/// Run the rule resolver, if any, before the write lock is taken, so a slow
+/// lookup only delays its own request. The limiter then uses the cached result.
+async fn resolve_rules(who: &str, route: &str) {
+    let resolver = global_limiter().read().await.rule_resolver();
+    if let Some(resolver) = resolver {
+        resolver.rules_for(who, route).await;
+    }
+}
+
 /// Check if a request should be allowed.
 pub async fn check_limit(who: &str, route: &str) -> bool {
-    if let Some(limiter) = GLOBAL_LIMITER.get() {
-        let mut limiter = limiter.write().await;
-        limiter.check_limit(who, route, false).await
-    } else {
-        panic!("Rate limiter not initialized! Call init_rate_limiter! first.");
-    }
+    resolve_rules(who, route).await;
+    let mut limiter = global_limiter().write().await;
+    limiter.check_limit(who, route, false).await
 }
 
 /// Check rate limit with override mode.
 pub async fn check_limit_override(who: &str, route: &str) -> bool {
-    if let Some(limiter) = GLOBAL_LIMITER.get() {
-        let mut limiter = limiter.write().await;
-        limiter.check_limit(who, route, true).await
-    } else {
-        panic!("Rate limiter not initialized! Call init_rate_limiter! first.");
+    resolve_rules(who, route).await;
+    let mut limiter = global_limiter().write().await;
+    limiter.check_limit(who, route, true).await
+}
+
+/// Check a request and report why it was allowed or rejected.
+pub async fn decide(who: &str, route: &str) -> Decision {
+    resolve_rules(who, route).await;
+    let mut limiter = global_limiter().write().await;
+    limiter.decide(who, route, false).await
+}
+
+/// Override-mode variant of `decide`.
+pub async fn decide_override(who: &str, route: &str) -> Decision {
+    resolve_rules(who, route).await;
+    let mut limiter = global_limiter().write().await;
+    limiter.decide(who, route, true).await
+}
+
+/// Reject every request from `who` for `duration`. Returns when the ban ends.
+pub async fn ban(who: &str, duration: Duration) -> u64 {
+    let limiter = global_limiter().read().await;
+    limiter.ban(who, duration).await
+}
+
+/// Lift a ban on `who`. Returns whether one was active.
+pub async fn unban(who: &str) -> bool {
+    let limiter = global_limiter().read().await;
+    limiter.unban(who).await
+}
+
+/// Check a request and get a token that can `refund` it if the handler fails early.
+pub async fn check_with_token(who: &str, route: &str) -> Result<QuotaToken, Decision> {
+    resolve_rules(who, route).await;
+    let mut limiter = global_limiter().write().await;
+    limiter.check_with_token(who, route, false).await
+}
+
+/// Override-mode variant of `check_with_token`.
+pub async fn check_with_token_override(who: &str, route: &str) -> Result<QuotaToken, Decision> {
+    resolve_rules(who, route).await;
+    let mut limiter = global_limiter().write().await;
+    limiter.check_with_token(who, route, true).await
+}
+
+/// Give back the quota consumed by the check that issued `token`.
+pub async fn refund(token: QuotaToken) -> bool {
+    let mut limiter = global_limiter().write().await;
+    limiter.refund(token).await
+}
+
+/// Count a failure against a route whose rule only counts failures.
+pub async fn record_failure(who: &str, route: &str) -> bool {
+    resolve_rules(who, route).await;
+    let mut limiter = global_limiter().write().await;
+    limiter.record_failure(who, route).await
+}
+
+/// Clear recorded failures for `who` on a failures-only route.
+pub async fn record_success(who: &str, route: &str) -> bool {
+    resolve_rules(who, route).await;
+    let mut limiter = global_limiter().write().await;
+    limiter.record_success(who, route).await
+}
+
+/// Post-response hook for HTTP integrations: count the outcome against a
+/// route whose rule counts matching responses.
+pub async fn record_response(who: &str, route: &str, status: u16) -> bool {
+    resolve_rules(who, route).await;
+    let mut limiter = global_limiter().write().await;
+    limiter.record_response(who, route, status).await
+}
+
+/// Wait until the request is admitted, giving up after `max_wait`.
+///
+/// Waiters for the same key are admitted in arrival order, up to
+/// `max_queue_len` of them; `max_wait` includes the time spent queued. The global lock is released while sleeping, so
+/// other requests keep flowing.
+pub async fn until_ready(
+    who: &str,
+    route: &str,
+    max_wait: Option<Duration>,
+) -> Result<Decision, Decision> {
+    wait_until_ready(who, route, false, max_wait).await
+}
+
+/// Override-mode variant of `until_ready`.
+pub async fn until_ready_override(
+    who: &str,
+    route: &str,
+    max_wait: Option<Duration>,
+) -> Result<Decision, Decision> {
+    wait_until_ready(who, route, true, max_wait).await
+}
+
+async fn wait_until_ready(
+    who: &str,
+    route: &str,
+    override_mode: bool,
+    max_wait: Option<Duration>,
+) -> Result<Decision, Decision> {
+    let deadline = max_wait.map(|d| Instant::now() + StdDuration::from_secs(d.as_seconds()));
+    let queues = global_limiter().read().await.wait_queues();
+    // The deadline also bounds the time spent behind earlier waiters.
+    let turn = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, queues.wait_turn(who, route)).await,
+        None => Ok(queues.wait_turn(who, route).await),
+    };
+    let _turn = match turn {
+        Ok(Some(turn)) => turn,
+        Ok(None) => {
+            telemetry::record_decision(who, route, "queue", false);
+            return Err(Decision::QueueFull {
+                max_queue_len: queues.max_len(),
+            });
+        }
+        Err(_) => {
+            telemetry::record_decision(who, route, "queue", false);
+            return Err(Decision::QueueTimeout {
+                max_wait: max_wait.map_or(0, |d| d.as_seconds()),
+            });
+        }
+    };
+    loop {
+        resolve_rules(who, route).await;
+        let decision = {
+            let mut limiter = global_limiter().write().await;
+            limiter.decide(who, route, override_mode).await
+        };
+        match limiter::wait_before_retry(decision, deadline) {
+            ControlFlow::Continue(wait) => tokio::time::sleep(wait).await,
+            ControlFlow::Break(result) => return result,
+        }
     }
 }
 
+/// Check the rate rules and take a concurrency slot, released when the `Permit` is dropped.
+pub async fn acquire_permit(who: &str, route: &str) -> Result<Permit, Decision> {
+    resolve_rules(who, route).await;
+    let mut limiter = global_limiter().write().await;
+    limiter.acquire_permit(who, route, false).await
+}
+
+/// Override-mode variant of `acquire_permit`.
+pub async fn acquire_permit_override(who: &str, route: &str) -> Result<Permit, Decision> {
+    resolve_rules(who, route).await;
+    let mut limiter = global_limiter().write().await;
+    limiter.acquire_permit(who, route, true).await
+}
+
+/// Evaluate every dimension rule that applies to `key` in one call.
+pub async fn decide_composite(key: &CompositeKey) -> Decision {
+    let mut limiter = global_limiter().write().await;
+    limiter.decide_composite(key).await
+}
+
+/// Current record for `who` on `route`, if one is being tracked.
+pub async fn get_record(who: &str, route: &str) -> Option<RequestRecord> {
+    let limiter = global_limiter().read().await;
+    limiter.get_record(who, route).await
+}
+
+/// Current global (all routes) record for `who`, if one is being tracked.
+pub async fn get_global_record(who: &str) -> Option<RequestRecord> {
+    let limiter = global_limiter().read().await;
+    limiter.get_global_record(who).await
+}
+
+/// Clear the record for `who` on `route`. Returns whether a record existed.
+pub async fn reset_limit(who: &str, route: &str) -> bool {
+    let mut limiter = global_limiter().write().await;
+    limiter.reset_limit(who, route).await
+}
+
+/// Clear every record for `who`, including its global record. Returns how many were removed.
+pub async fn reset_identity(who: &str) -> usize {
+    let mut limiter = global_limiter().write().await;
+    limiter.reset_identity(who).await
+}
+
+/// Clear the records of every identity on `route`. Returns how many were removed.
+pub async fn reset_route(route: &str) -> usize {
+    let mut limiter = global_limiter().write().await;
+    limiter.reset_route(route).await
+}
+
+/// Drop cached resolver results for `who`, e.g. after their quota changed.
+pub async fn invalidate_resolved_rules(who: &str) {
+    let limiter = global_limiter().read().await;
+    limiter.invalidate_resolved_rules(who);
+}
+
+/// Snapshot of what the global limiter is tracking, with the `top_n` heaviest identities.
+pub async fn get_stats(top_n: usize) -> LimiterStats {
+    let limiter = global_limiter().read().await;
+    limiter.stats(top_n).await
+}
+
+/// Send counters changed since the last flush to replication peers.
+pub async fn flush_deltas() -> usize {
+    let mut limiter = global_limiter().write().await;
+    limiter.flush_deltas().await
+}
+
+/// Merge counter deltas received from replication peers.
+pub async fn apply_deltas(deltas: &[CounterDelta]) {
+    let mut limiter = global_limiter().write().await;
+    limiter.apply_deltas(deltas);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::types::Duration;
     use std::time::Duration as StdDuration;
 
+    // Tests share the global limiter, so every test installs the same config.
+    async fn init() {
+        GLOBAL_LIMITER
+            .get_or_init(|| async {
+                let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(1), 2))
+                    .add_route_rule("/slow", RuleConfig::new(Duration::seconds(30), 1));
+                Arc::new(RwLock::new(RateLimiter::new(config).await))
+            })
+            .await;
+    }
+
     #[tokio::test]
     async fn test_basic_rate_limiting() {
-        // We re-create the limiter for each test, which isn't ideal with a global static.
-        // For a simple test suite, this works by overwriting.
-        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(1), 2));
-        let limiter = RateLimiter::new(config).await;
-        let _ = GLOBAL_LIMITER.set(Arc::new(RwLock::new(limiter)));
+        init().await;
 
         let who = "test_ip";
         let route = "/test";
@@ -143,4 +394,22 @@ mod tests {
         tokio::time::sleep(StdDuration::from_secs(1)).await;
         assert!(check_limit(who, route).await);
     }
+
+    #[tokio::test]
+    async fn test_queued_waiter_respects_max_wait() {
+        init().await;
+        let who = "queued_ip";
+        assert!(check_limit(who, "/slow").await);
+
+        // The head of the queue waits out the 30 second window.
+        let head = tokio::spawn(until_ready(who, "/slow", None));
+        tokio::time::sleep(StdDuration::from_millis(50)).await;
+
+        let started = Instant::now();
+        let result = until_ready(who, "/slow", Some(Duration::seconds(1))).await;
+        assert!(started.elapsed() < StdDuration::from_millis(1500));
+        assert_eq!(result, Err(Decision::QueueTimeout { max_wait: 1 }));
+
+        head.abort();
+    }
 }
@@ -1,128 +1,708 @@
 /* src/limiter.rs */
 
-use crate::config::LimiterConfig;
+use crate::ban::BanList;
+use crate::composite::{CompositeKey, DimensionRule};
+use crate::concurrency::{InFlight, Permit};
+use crate::config::{LimiterConfig, RouteMode, UnmatchedRoute};
 use crate::gc::GarbageCollector;
-use crate::types::{RequestRecord, RuleConfig};
-use std::collections::HashMap;
+use crate::key::LimitKey;
+use crate::queue::WaitQueues;
+use crate::replication::{CounterDelta, DeltaTransport, Replicator};
+use crate::resolver::{ResolvedRules, RuleResolver};
+use crate::stats::{self, GcReport, LimiterStats};
+use crate::store::Store;
+use crate::telemetry;
+use crate::types::{
+    ChargeMark, CountMode, Decision, Duration, RequestRecord, RuleConfig, Scope, ScopeRef,
+    current_timestamp,
+};
+use std::borrow::{Borrow, Cow};
+use std::future::Future;
+use std::ops::ControlFlow;
 use std::sync::Arc;
+use std::time::{Duration as StdDuration, SystemTime, UNIX_EPOCH};
 use tokio::sync::RwLock;
+use tokio::time::{Duration as TokioDuration, Instant};
 
-/// Main rate limiter implementation
-pub struct RateLimiter {
+/// Main rate limiter implementation, counting requests per identity of type `K`.
+///
+/// The default `str` keys work with any identifier; `IpAddr` or `u64` keys
+/// store records without formatting them as strings.
+pub struct RateLimiter<K: ?Sized + LimitKey = str> {
     config: LimiterConfig,
-    records: Arc<RwLock<HashMap<String, HashMap<String, RequestRecord>>>>,
+    records: Arc<RwLock<Store<K>>>,
+    last_gc: Arc<RwLock<Option<GcReport>>>,
+    bans: Arc<RwLock<BanList>>,
+    in_flight: InFlight,
+    wait_queues: Arc<WaitQueues>,
+    replicator: Option<Replicator<K>>,
+    resolver: Option<RuleResolver>,
+    /// Resolver lookups are made by the caller before locking the limiter, so
+    /// serve cached results, even stale ones, instead of awaiting the resolver.
+    rules_prefetched: bool,
 }
 
-impl RateLimiter {
+impl<K: ?Sized + LimitKey> RateLimiter<K> {
     pub async fn new(config: LimiterConfig) -> Self {
-        let records = Arc::new(RwLock::new(HashMap::new()));
+        let records = Arc::new(RwLock::new(Store::new(config.max_memory)));
+        let last_gc = Arc::new(RwLock::new(None));
+        let bans = Arc::new(RwLock::new(BanList::default()));
 
-        let gc = GarbageCollector::new(records.clone(), config.clone());
+        let gc = GarbageCollector::<K>::new(
+            records.clone(),
+            config.clone(),
+            last_gc.clone(),
+            bans.clone(),
+        );
         tokio::spawn(async move {
             gc.start().await;
         });
 
-        Self { config, records }
+        let wait_queues = Arc::new(WaitQueues::new(config.max_queue_len));
+        Self {
+            config,
+            records,
+            last_gc,
+            bans,
+            in_flight: InFlight::default(),
+            wait_queues,
+            replicator: None,
+            resolver: None,
+            rules_prefetched: false,
+        }
+    }
+
+    /// Look up rules per `(who, route)` with an async callback, e.g. from a database.
+    ///
+    /// Results, including `None` for "use the configured rules", are cached for
+    /// `ttl` so the resolver is not called on every request. Resolved rules take
+    /// precedence over tiers and the base configuration. The garbage collector
+    /// only knows configured rules, so keep resolved intervals within the
+    /// longest configured one.
+    pub fn with_rule_resolver<F, Fut>(mut self, ttl: Duration, resolve: F) -> Self
+    where
+        F: Fn(String, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<ResolvedRules>> + Send + 'static,
+    {
+        let ttl = TokioDuration::from_secs(ttl.as_seconds());
+        self.resolver = Some(RuleResolver::new(ttl, resolve));
+        self
+    }
+
+    /// Drop cached resolver results for `who`, e.g. after their plan changed.
+    pub fn invalidate_resolved_rules(&self, who: &K) {
+        if let Some(resolver) = &self.resolver {
+            resolver.invalidate(&who.name());
+        }
+    }
+
+    /// A handle on the rule resolver that shares its cache, for looking rules
+    /// up without holding a lock on the limiter.
+    pub(crate) fn rule_resolver(&self) -> Option<RuleResolver> {
+        self.resolver.clone()
+    }
+
+    /// Let callers resolve rules through `rule_resolver` before locking the limiter.
+    pub(crate) fn prefetch_rules(&mut self) {
+        self.rules_prefetched = true;
+    }
+
+    async fn resolved_rules(&self, who: &K, route: &str) -> Option<ResolvedRules> {
+        let resolver = self.resolver.as_ref()?;
+        let who = who.name();
+        if self.rules_prefetched
+            && let Some(rules) = resolver.cached(&who, route)
+        {
+            return rules;
+        }
+        resolver.rules_for(&who, route).await
+    }
+
+    /// Replicate counters with peer nodes through `transport`.
+    ///
+    /// Limits are then enforced against the merged view of local and peer counts.
+    /// Call `flush_deltas` to publish local changes and `apply_deltas` with
+    /// whatever the transport delivers from peers.
+    pub fn with_replication(
+        mut self,
+        node_id: impl Into<String>,
+        transport: Arc<dyn DeltaTransport>,
+    ) -> Self {
+        self.replicator = Some(Replicator::new(node_id.into(), transport));
+        self
+    }
+
+    /// Send counters changed since the last flush to peers. Returns how many were sent.
+    pub async fn flush_deltas(&mut self) -> usize {
+        let Some(replicator) = self.replicator.as_mut() else {
+            return 0;
+        };
+        let records = self.records.read().await;
+        replicator.flush(&records)
+    }
+
+    /// Merge counter deltas received from peers.
+    pub fn apply_deltas(&mut self, deltas: &[CounterDelta]) {
+        let max_age = self.config.max_interval().as_seconds() + 300;
+        if let Some(replicator) = self.replicator.as_mut() {
+            replicator.apply(deltas, max_age);
+        }
+    }
+
+    pub async fn check_limit(&mut self, who: &K, route: &str, override_mode: bool) -> bool {
+        self.decide(who, route, override_mode).await.is_allowed()
     }
 
-    pub async fn check_limit(&mut self, who: &str, route: &str, override_mode: bool) -> bool {
-        let (global_rule, route_rule_opt) = if override_mode {
-            let rule = if self.config.has_route_rule(route) {
-                Some(self.config.get_rule_for_route(route))
+    /// Check the rate rules and take a concurrency slot for the request.
+    ///
+    /// Routes with a concurrency limit admit at most that many requests per
+    /// identity at once; the slot is released when the returned `Permit` is
+    /// dropped. Requests rejected for concurrency do not use up rate quota.
+    /// Access lists, bans and `Exempt`/`Deny` route modes are applied before
+    /// the limit, so allowlisted identities and exempt routes are not capped.
+    pub async fn acquire_permit(
+        &mut self,
+        who: &K,
+        route: &str,
+        override_mode: bool,
+    ) -> Result<Permit, Decision> {
+        let uncapped = match self.config.concurrency_limits.get(route) {
+            Some(&max_in_flight) => {
+                let (mode, name) = self.in_flight_scope(who, route).await;
+                let bypassed = matches!(mode, RouteMode::Exempt | RouteMode::Deny)
+                    || self.access_decision(&[who], &[who]).await.is_some();
+                (!bypassed).then_some((max_in_flight, name))
+            }
+            None => None,
+        };
+        let Some((max_in_flight, name)) = uncapped else {
+            let decision = self.decide(who, route, override_mode).await;
+            return if decision.is_allowed() {
+                Ok(Permit::unlimited())
             } else {
-                None
+                Err(decision)
             };
-            (None, rule)
+        };
+
+        let concurrency_limited = || Decision::ConcurrencyLimited {
+            rule: route.to_string(),
+            max_in_flight,
+        };
+        if self.in_flight.current(&name, route) >= max_in_flight {
+            telemetry::record_decision(who, route, "concurrency", false);
+            return Err(concurrency_limited());
+        }
+
+        let decision = self.decide(who, route, override_mode).await;
+        if !decision.is_allowed() {
+            return Err(decision);
+        }
+        // Permits can only have been released while the rules were checked.
+        self.in_flight
+            .try_acquire(&name, route, max_in_flight)
+            .ok_or_else(concurrency_limited)
+    }
+
+    pub(crate) fn wait_queues(&self) -> Arc<WaitQueues> {
+        self.wait_queues.clone()
+    }
+
+    /// Requests from `who` currently holding a permit for `route`.
+    pub async fn in_flight(&self, who: &K, route: &str) -> u32 {
+        let (_, name) = self.in_flight_scope(who, route).await;
+        self.in_flight.current(&name, route)
+    }
+
+    /// The route mode `decide` applies to `who` on `route`, and the key its
+    /// permits are counted under, normalized like its route record.
+    async fn in_flight_scope(&self, who: &K, route: &str) -> (RouteMode, String) {
+        let resolved = self.resolved_rules(who, route).await;
+        let (default_rule, matched_rule) =
+            effective_rules(&self.config, who, route, resolved.as_ref());
+        let rule = matched_rule.unwrap_or(default_rule);
+        (
+            self.config.route_mode(route, matched_rule.is_some()),
+            rule.normalize_key(who).name().into_owned(),
+        )
+    }
+
+    /// Wait until the rules admit the request, then count it.
+    ///
+    /// Sleeps for the `retry_after` of each rejection. Gives up with the last
+    /// decision if waiting would exceed `max_wait`, or immediately when the
+    /// identity is denylisted or banned. Cancel by dropping the future, e.g.
+    /// via `tokio::select!`.
+    ///
+    /// This holds `&mut self` while sleeping, so the limiter serves nothing
+    /// else until it returns. The crate-level `until_ready` releases the global
+    /// lock between attempts and admits waiters in arrival order instead.
+    pub async fn until_ready(
+        &mut self,
+        who: &K,
+        route: &str,
+        override_mode: bool,
+        max_wait: Option<Duration>,
+    ) -> Result<Decision, Decision> {
+        let deadline = max_wait.map(|d| Instant::now() + TokioDuration::from_secs(d.as_seconds()));
+        loop {
+            let decision = self.decide(who, route, override_mode).await;
+            match wait_before_retry(decision, deadline) {
+                ControlFlow::Continue(wait) => tokio::time::sleep(wait).await,
+                ControlFlow::Break(result) => return result,
+            }
+        }
+    }
+
+    /// Like `check_limit`, but reports why the request was allowed or rejected.
+    pub async fn decide(&mut self, who: &K, route: &str, override_mode: bool) -> Decision {
+        self.decide_charged(who, route, override_mode, None).await
+    }
+
+    /// Like `decide`, but hands back a token that can later `refund` the request,
+    /// e.g. when the handler fails before doing any real work.
+    pub async fn check_with_token(
+        &mut self,
+        who: &K,
+        route: &str,
+        override_mode: bool,
+    ) -> Result<QuotaToken<K>, Decision> {
+        let mut charges = Vec::new();
+        let decision = self
+            .decide_charged(who, route, override_mode, Some(&mut charges))
+            .await;
+        if decision.is_allowed() {
+            Ok(QuotaToken { charges })
         } else {
-            let rule = if self.config.has_route_rule(route) {
-                self.config.get_rule_for_route(route)
-            } else {
-                &self.config.default_rule
+            Err(decision)
+        }
+    }
+
+    /// Give back the quota consumed by the check that issued `token`.
+    ///
+    /// Returns whether anything was refunded; records that have since moved
+    /// to a new window or been cleaned up are left alone. Refunds are local
+    /// and not propagated to replication peers.
+    pub async fn refund(&mut self, token: QuotaToken<K>) -> bool {
+        let mut records = self.records.write().await;
+        let mut refunded = false;
+        for charge in token.charges {
+            if let Some(mut record) =
+                records.get_mut(charge.key.borrow(), charge.scope.as_scope_ref())
+            {
+                refunded |= record.refund(charge.mark);
+            }
+        }
+        refunded
+    }
+
+    /// Count a failure (e.g. a failed login) against the route's rule when it
+    /// only counts failures. Returns whether the failure was recorded.
+    pub async fn record_failure(&mut self, who: &K, route: &str) -> bool {
+        let rule = self.route_rule_for(who, route).await;
+        if !matches!(rule.count_mode, CountMode::FailuresOnly) {
+            return false;
+        }
+        self.count_outcome(who, route, &rule).await;
+        true
+    }
+
+    /// Post-response hook: count a response with `status` against the route's
+    /// rule when the rule counts responses and its filter matches.
+    /// Returns whether the response was counted.
+    pub async fn record_response(&mut self, who: &K, route: &str, status: u16) -> bool {
+        let rule = self.route_rule_for(who, route).await;
+        match &rule.count_mode {
+            CountMode::Responses(filter) if filter.matches(status) => {}
+            _ => return false,
+        }
+        self.count_outcome(who, route, &rule).await;
+        true
+    }
+
+    async fn count_outcome(&mut self, who: &K, route: &str, rule: &RuleConfig) {
+        let key = rule.normalize_key(who);
+        let mut records = self.records.write().await;
+        Self::update_record(
+            &mut records,
+            self.replicator.as_mut(),
+            &key,
+            ScopeRef::Route(route),
+            rule,
+        );
+    }
+
+    /// Clear recorded failures after a success, e.g. a correct login.
+    /// Returns whether there were any.
+    pub async fn record_success(&mut self, who: &K, route: &str) -> bool {
+        let rule = self.route_rule_for(who, route).await;
+        if !matches!(rule.count_mode, CountMode::FailuresOnly) {
+            return false;
+        }
+        let key = rule.normalize_key(who).into_owned();
+        self.reset_limit(key.borrow(), route).await
+    }
+
+    /// The rule `decide` applies to `route` itself, ignoring the global limit.
+    async fn route_rule_for(&mut self, who: &K, route: &str) -> RuleConfig {
+        let resolved = self.resolved_rules(who, route).await.unwrap_or_default();
+        let (default_rule, matched_rule) = self.config.rules_for(who, route);
+        resolved
+            .route_rule
+            .or_else(|| matched_rule.cloned())
+            .or(resolved.default_rule)
+            .unwrap_or_else(|| default_rule.clone())
+    }
+
+    /// `decide`, recording what was counted into `charges` when given.
+    async fn decide_charged(
+        &mut self,
+        who: &K,
+        route: &str,
+        override_mode: bool,
+        mut charges: Option<&mut Vec<Charge<K>>>,
+    ) -> Decision {
+        if let Some((decision, reason)) = self.access_decision(&[who], &[who]).await {
+            telemetry::record_decision(who, route, reason, decision.is_allowed());
+            return decision;
+        }
+
+        let resolved = self.resolved_rules(who, route).await;
+        let (default_rule, matched_rule) =
+            effective_rules(&self.config, who, route, resolved.as_ref());
+        if matched_rule.is_none()
+            && !self.config.route_modes.contains_key(route)
+            && let Some(hook) = &self.config.unmatched_route_hook
+        {
+            hook.report(route);
+        }
+        // Override mode turns the default `Inherit` into `Replace` without
+        // falling back to the default rule. Modes configured for the route
+        // apply as they are, whichever kind of check is made.
+        let override_mode = override_mode && !self.config.route_modes.contains_key(route);
+        let (global_rule, route_rule_opt) =
+            match self.config.route_mode(route, matched_rule.is_some()) {
+                RouteMode::Exempt => {
+                    telemetry::record_decision(who, route, "exempt", true);
+                    return Decision::Allowed;
+                }
+                RouteMode::Deny => {
+                    telemetry::record_decision(who, route, "route_deny", false);
+                    return Decision::RouteDenied;
+                }
+                _ if override_mode && matched_rule.is_some() => (None, matched_rule),
+                _ if override_mode => match &self.config.unmatched_override {
+                    UnmatchedRoute::Allow => (None, None),
+                    UnmatchedRoute::Deny => {
+                        telemetry::record_decision(who, route, "unmatched", false);
+                        return Decision::RouteDenied;
+                    }
+                    UnmatchedRoute::CatchAll(rule) => (None, Some(rule)),
+                },
+                RouteMode::Inherit => (
+                    Some(default_rule),
+                    Some(matched_rule.unwrap_or(default_rule)),
+                ),
+                RouteMode::Replace => (None, Some(matched_rule.unwrap_or(default_rule))),
             };
-            (Some(&self.config.default_rule), Some(rule))
-        };
 
-        if override_mode && route_rule_opt.is_none() {
-            return true;
+        if route_rule_opt.is_none() {
+            telemetry::record_decision(who, route, "unmatched", true);
+            return Decision::Allowed;
         }
 
+        let route_label = if matched_rule.is_some() {
+            route
+        } else if override_mode {
+            "catch_all"
+        } else {
+            "default"
+        };
+        let global_who = global_rule.map_or(Cow::Borrowed(who), |rule| rule.normalize_key(who));
+        let global_who = global_who.as_ref();
+        let route_who = route_rule_opt.map_or(Cow::Borrowed(who), |rule| rule.normalize_key(who));
+        let route_who = route_who.as_ref();
+        let route_scope = ScopeRef::Route(route);
         let records = self.records.read().await;
 
-        let mut allow = true;
+        // (rule label, record key, record scope, rule, retry after)
+        let mut denied = None;
+        // (rule label, retry after) of the first shadow rule that was exceeded
+        let mut shadowed = None;
 
-        if let Some(rule) = global_rule {
-            let global_key = format!("__global__{}", who);
-            if self.is_record_exceeded(&records, &global_key, "__global__", rule) {
-                allow = false;
+        if let Some(rule) = global_rule
+            && let Some(retry_after) =
+                self.exceeded_retry_after(&records, global_who, ScopeRef::Global, rule)
+        {
+            if rule.shadow {
+                shadowed = Some(("__global__", retry_after));
+            } else {
+                denied = Some((
+                    "__global__",
+                    global_who,
+                    ScopeRef::Global,
+                    rule,
+                    retry_after,
+                ));
             }
         }
 
-        if allow {
-            if let Some(rule) = route_rule_opt {
-                if self.is_record_exceeded(&records, who, route, rule) {
-                    allow = false;
-                }
+        if denied.is_none()
+            && let Some(rule) = route_rule_opt
+            && let Some(retry_after) =
+                self.exceeded_retry_after(&records, route_who, route_scope, rule)
+        {
+            if rule.shadow {
+                shadowed = shadowed.or(Some((route_label, retry_after)));
+            } else {
+                denied = Some((route_label, route_who, route_scope, rule, retry_after));
             }
         }
 
         drop(records);
 
-        if allow {
+        if let Some((rule_label, key, scope, rule, retry_after)) = denied {
+            telemetry::record_decision(who, route, rule_label, false);
+            let retry_after = retry_after.max(self.penalize(key, scope, rule).await);
+            if let Some(policy) = &self.config.ban_policy {
+                self.bans.write().await.record_denial(&who.name(), policy);
+            }
+            return Decision::Limited {
+                rule: rule_label.to_string(),
+                retry_after,
+            };
+        }
+        if let Some((rule_label, _)) = shadowed {
+            telemetry::record_shadow_violation(who, route, rule_label);
+        }
+        telemetry::record_decision(who, route, route_label, true);
+
+        {
             let mut records = self.records.write().await;
-            if let Some(rule) = global_rule {
-                let global_key = format!("__global__{}", who);
-                self.update_record(&mut records, &global_key, "__global__", rule);
+            if let Some(rule) = global_rule.filter(|rule| rule.counts_requests()) {
+                let mark = Self::update_record(
+                    &mut records,
+                    self.replicator.as_mut(),
+                    global_who,
+                    ScopeRef::Global,
+                    rule,
+                );
+                if let Some(charges) = charges.as_deref_mut() {
+                    charges.push(Charge::new(global_who, ScopeRef::Global, mark));
+                }
             }
-            if let Some(rule) = route_rule_opt {
-                self.update_record(&mut records, who, route, rule);
+            if let Some(rule) = route_rule_opt.filter(|rule| rule.counts_requests()) {
+                let mark = Self::update_record(
+                    &mut records,
+                    self.replicator.as_mut(),
+                    route_who,
+                    route_scope,
+                    rule,
+                );
+                if let Some(charges) = charges {
+                    charges.push(Charge::new(route_who, route_scope, mark));
+                }
             }
         }
 
-        allow
+        match shadowed {
+            Some((rule_label, retry_after)) => Decision::Shadowed {
+                rule: rule_label.to_string(),
+                retry_after,
+            },
+            None => Decision::Allowed,
+        }
     }
 
-    fn is_record_exceeded(
+    /// Denylist, ban and allowlist checks shared by every kind of decision,
+    /// returning the decision and its telemetry label if one of them applies.
+    /// Only `allowlisted` identities are matched against the allowlist.
+    async fn access_decision<Q: ?Sized + LimitKey>(
         &self,
-        records: &HashMap<String, HashMap<String, RequestRecord>>,
-        who: &str,
-        route: &str,
+        identities: &[&Q],
+        allowlisted: &[&Q],
+    ) -> Option<(Decision, &'static str)> {
+        if identities
+            .iter()
+            .any(|who| self.config.denylist.matches(*who))
+        {
+            return Some((Decision::Denylisted, "denylist"));
+        }
+        let bans = self.bans.read().await;
+        if !bans.is_empty()
+            && let Some(until) = identities
+                .iter()
+                .find_map(|who| bans.banned_until(&who.name()))
+        {
+            return Some((Decision::Banned { until }, "ban"));
+        }
+        if allowlisted
+            .iter()
+            .any(|who| self.config.allowlist.matches(*who))
+        {
+            return Some((Decision::Allowlisted, "allowlist"));
+        }
+        None
+    }
+
+    /// Register a violation of `rule` if it carries a penalty, returning the block in seconds.
+    async fn penalize(&self, key: &K, scope: ScopeRef<'_>, rule: &RuleConfig) -> u64 {
+        let Some(penalty) = &rule.penalty else {
+            return 0;
+        };
+        let mut records = self.records.write().await;
+        let mut record = records.get_or_insert(key, scope, || {
+            RequestRecord::new(rule.interval.is_short_interval())
+        });
+        record.register_violation(penalty)
+    }
+
+    /// Seconds until `rule` would allow another request, or `None` if it allows one now.
+    fn exceeded_retry_after(
+        &self,
+        records: &Store<K>,
+        who: &K,
+        scope: ScopeRef<'_>,
         rule: &RuleConfig,
-    ) -> bool {
+    ) -> Option<u64> {
         let is_short_interval = rule.interval.is_short_interval();
         let window_size = rule.interval.as_seconds();
 
-        if let Some(route_records) = records.get(who) {
-            if let Some(record) = route_records.get(route) {
-                return record.is_limit_exceeded(rule.limit, is_short_interval, window_size);
-            }
+        let record = records.get(who, scope);
+        if let Some(record) = record
+            && record.is_penalized()
+        {
+            return Some(record.retry_after(is_short_interval, window_size));
+        }
+
+        let local = record.map_or(0, |record| {
+            record.current_count(is_short_interval, window_size)
+        });
+        let remote = self.replicator.as_ref().map_or(0, |r| {
+            r.remote_count(who, scope, window_size, rule.ip_aggregation.as_ref())
+        });
+
+        if local.saturating_add(remote) < rule.limit {
+            return None;
         }
-        false
+        // Peer counts carry no timestamps, so unless local requests alone fill
+        // the limit, assume a full window. Never report 0: callers retry after it.
+        let retry_after = match record {
+            Some(record) if local >= rule.limit => {
+                record.retry_after(is_short_interval, window_size)
+            }
+            _ => window_size,
+        };
+        Some(retry_after.max(1))
     }
 
     fn update_record(
-        &self,
-        records: &mut HashMap<String, HashMap<String, RequestRecord>>,
-        who: &str,
-        route: &str,
+        records: &mut Store<K>,
+        replicator: Option<&mut Replicator<K>>,
+        who: &K,
+        scope: ScopeRef<'_>,
         rule: &RuleConfig,
-    ) {
+    ) -> ChargeMark {
         let is_short_interval = rule.interval.is_short_interval();
         let window_size = rule.interval.as_seconds();
 
-        let route_records = records.entry(who.to_string()).or_insert_with(HashMap::new);
-        let record = route_records
-            .entry(route.to_string())
-            .or_insert_with(|| RequestRecord::new(is_short_interval));
+        let mut record =
+            records.get_or_insert(who, scope, || RequestRecord::new(is_short_interval));
+
+        let mark = record.add_request(is_short_interval, window_size);
 
-        record.add_request(is_short_interval, window_size);
+        if let Some(replicator) = replicator {
+            replicator.mark_dirty(
+                who,
+                scope,
+                is_short_interval,
+                window_size,
+                rule.ip_aggregation.as_ref(),
+            );
+        }
+        mark
     }
 
-    #[allow(dead_code)]
-    pub async fn get_stats(&self) -> (usize, usize) {
+    /// Snapshot of tracked identities, memory and GC activity, including the
+    /// `top_n` identities with the most counted requests.
+    pub async fn stats(&self, top_n: usize) -> LimiterStats {
+        let last_gc = self.last_gc.read().await.clone();
         let records = self.records.read().await;
-        let total_users = records.len();
-        let total_routes = records.values().map(|r| r.len()).sum();
-        (total_users, total_routes)
+        stats::collect(&records, last_gc, top_n)
+    }
+
+    /// Reject every request from `who` for `duration`. Returns when the ban ends.
+    pub async fn ban(&self, who: &K, duration: Duration) -> u64 {
+        self.bans.write().await.ban(&who.name(), duration)
+    }
+
+    /// Lift a ban on `who`. Returns whether one was active.
+    pub async fn unban(&self, who: &K) -> bool {
+        self.bans.write().await.unban(&who.name())
+    }
+
+    /// When the active ban on `who` ends, if there is one.
+    pub async fn banned_until(&self, who: &K) -> Option<u64> {
+        self.bans.read().await.banned_until(&who.name())
+    }
+
+    /// Current record for `who` on `route`, if one is being tracked.
+    pub async fn get_record(&self, who: &K, route: &str) -> Option<RequestRecord> {
+        let records = self.records.read().await;
+        records.get(who, ScopeRef::Route(route)).cloned()
+    }
+
+    /// Current global (all routes) record for `who`, if one is being tracked.
+    pub async fn get_global_record(&self, who: &K) -> Option<RequestRecord> {
+        let records = self.records.read().await;
+        records.get(who, ScopeRef::Global).cloned()
+    }
+
+    /// Current record for `who` in any scope, e.g. a dimension rule's bucket.
+    pub async fn get_scoped_record(&self, who: &K, scope: &Scope) -> Option<RequestRecord> {
+        let records = self.records.read().await;
+        records.get(who, scope.as_scope_ref()).cloned()
+    }
+
+    /// Clear the record for `who` on `route`. The global record is left alone;
+    /// use `reset_identity` to fully unblock someone.
+    pub async fn reset_limit(&mut self, who: &K, route: &str) -> bool {
+        let mut records = self.records.write().await;
+        let removed = records
+            .id(ScopeRef::Route(route))
+            .and_then(|id| records.remove(who, id))
+            .is_some();
+
+        if let Some(replicator) = self.replicator.as_mut() {
+            let name = who.name();
+            replicator.forget(|w, s| w == name && s.as_scope_ref() == ScopeRef::Route(route));
+        }
+        removed
+    }
+
+    /// Clear every record for `who`, including its global record.
+    pub async fn reset_identity(&mut self, who: &K) -> usize {
+        let mut records = self.records.write().await;
+        let removed = records.remove_identity(who);
+
+        if let Some(replicator) = self.replicator.as_mut() {
+            let name = who.name();
+            replicator.forget(|w, _| w == name);
+        }
+        removed
+    }
+
+    /// Clear the records of every identity on `route`.
+    pub async fn reset_route(&mut self, route: &str) -> usize {
+        let mut records = self.records.write().await;
+        let removed = match records.id(ScopeRef::Route(route)) {
+            Some(id) => records.retain(|_, scope, _| scope != id),
+            None => 0,
+        };
+
+        if let Some(replicator) = self.replicator.as_mut() {
+            replicator.forget(|_, s| s.as_scope_ref() == ScopeRef::Route(route));
+        }
+        removed
     }
 
     #[cfg(test)]
@@ -133,11 +713,179 @@ impl RateLimiter {
     }
 }
 
+impl RateLimiter {
+    /// Evaluate every dimension rule whose dimensions are all present in `key`.
+    ///
+    /// Each rule keeps its own records, so one call can enforce e.g. "per API
+    /// key per route", "per IP" and "per tenant" together. The request is only
+    /// counted if every applicable rule allows it. Denylists and bans are
+    /// matched against each dimension value; the allowlist only against the
+    /// configured `allowlist_dimension`, since other values may be client-supplied.
+    pub async fn decide_composite(&mut self, key: &CompositeKey) -> Decision {
+        let who = key.to_string();
+        let route = key.get("route").unwrap_or_default();
+        let values: Vec<&str> = key.values().collect();
+        let trusted: Vec<&str> = self
+            .config
+            .allowlist_dimension
+            .as_deref()
+            .and_then(|dimension| key.get(dimension))
+            .into_iter()
+            .collect();
+        if let Some((decision, reason)) = self.access_decision(&values, &trusted).await {
+            telemetry::record_decision(&who, route, reason, decision.is_allowed());
+            return decision;
+        }
+
+        let buckets: Vec<(&DimensionRule, String, ScopeRef)> = self
+            .config
+            .dimension_rules
+            .iter()
+            .filter_map(|d| Some((d, key.bucket(d)?, d.scope())))
+            .collect();
+        if buckets.is_empty() {
+            telemetry::record_decision(&who, route, "unmatched", true);
+            return Decision::Allowed;
+        }
+
+        let records = self.records.read().await;
+        let exceeded: Vec<_> = buckets
+            .iter()
+            .filter_map(|(d, bucket, scope)| {
+                self.exceeded_retry_after(&records, bucket, *scope, &d.rule)
+                    .map(|retry_after| (*d, bucket, scope, retry_after))
+            })
+            .collect();
+        drop(records);
+        let denied = exceeded.iter().find(|(d, ..)| !d.rule.shadow).copied();
+        let shadowed = exceeded
+            .first()
+            .map(|(d, _, _, retry_after)| (*d, *retry_after));
+
+        if let Some((d, bucket, scope, retry_after)) = denied {
+            telemetry::record_decision(&who, route, &d.name, false);
+            let retry_after = retry_after.max(self.penalize(bucket, *scope, &d.rule).await);
+            if let Some(policy) = &self.config.ban_policy
+                && let Some(banned) = self
+                    .config
+                    .ban_dimension
+                    .as_deref()
+                    .and_then(|dimension| key.get(dimension))
+            {
+                self.bans.write().await.record_denial(banned, policy);
+            }
+            return Decision::Limited {
+                rule: d.name.clone(),
+                retry_after,
+            };
+        }
+
+        let mut records = self.records.write().await;
+        for (d, bucket, scope) in &buckets {
+            telemetry::record_decision(&who, route, &d.name, true);
+            Self::update_record(
+                &mut records,
+                self.replicator.as_mut(),
+                bucket,
+                *scope,
+                &d.rule,
+            );
+        }
+
+        match shadowed {
+            Some((d, retry_after)) => {
+                telemetry::record_shadow_violation(&who, route, &d.name);
+                Decision::Shadowed {
+                    rule: d.name.clone(),
+                    retry_after,
+                }
+            }
+            None => Decision::Allowed,
+        }
+    }
+}
+
+/// Proof of a granted request that can be handed back to `refund` it.
+#[derive(Debug)]
+#[must_use = "drop the token to keep the request counted, or pass it to `refund`"]
+pub struct QuotaToken<K: ?Sized + LimitKey = str> {
+    charges: Vec<Charge<K>>,
+}
+
+#[derive(Debug)]
+struct Charge<K: ?Sized + LimitKey> {
+    key: K::Owned,
+    scope: Scope,
+    mark: ChargeMark,
+}
+
+impl<K: ?Sized + LimitKey> Charge<K> {
+    fn new(key: &K, scope: ScopeRef<'_>, mark: ChargeMark) -> Self {
+        Self {
+            key: key.to_owned(),
+            scope: scope.to_scope(),
+            mark,
+        }
+    }
+}
+
+/// The default and route rule for `who` on `route`, with `resolved` rules
+/// taking precedence over configured ones.
+fn effective_rules<'a, K: ?Sized + LimitKey>(
+    config: &'a LimiterConfig,
+    who: &K,
+    route: &str,
+    resolved: Option<&'a ResolvedRules>,
+) -> (&'a RuleConfig, Option<&'a RuleConfig>) {
+    let (mut default_rule, mut matched_rule) = config.rules_for(who, route);
+    if let Some(resolved) = resolved {
+        if let Some(rule) = &resolved.default_rule {
+            default_rule = rule;
+        }
+        if let Some(rule) = &resolved.route_rule {
+            matched_rule = Some(rule);
+        }
+    }
+    (default_rule, matched_rule)
+}
+
+/// How long to sleep before retrying after `decision`, or the final result if
+/// there is no point in waiting.
+pub(crate) fn wait_before_retry(
+    decision: Decision,
+    deadline: Option<Instant>,
+) -> ControlFlow<Result<Decision, Decision>, TokioDuration> {
+    let retry_after = match &decision {
+        Decision::Limited { retry_after, .. } => *retry_after,
+        _ if decision.is_allowed() => return ControlFlow::Break(Ok(decision)),
+        _ => return ControlFlow::Break(Err(decision)),
+    };
+
+    // Timestamps have second resolution, so wake right as the blocking second ends.
+    let ready_at = UNIX_EPOCH + StdDuration::from_secs(current_timestamp() + retry_after);
+    let wait = ready_at
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+
+    match deadline {
+        Some(deadline) if Instant::now() + wait > deadline => ControlFlow::Break(Err(decision)),
+        _ => ControlFlow::Continue(wait),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::{Duration, RuleConfig};
+    use crate::access::IpAggregation;
+    use crate::ban::BanPolicy;
+    use crate::composite::CompositeKey;
+    use crate::replication::ChannelTransport;
+    use crate::resolver::ResolvedRules;
+    use crate::stats::GcMode;
+    use crate::tier::TierConfig;
+    use crate::types::{Duration, Penalty, ResponseFilter, RuleConfig, Scope};
     use std::time::Duration as StdDuration;
+    use tokio::sync::mpsc;
 
     #[tokio::test]
     async fn test_rate_limiting_basic() {
@@ -236,4 +984,1070 @@ mod tests {
         assert!(limiter.check_limit("user2", route, false).await);
         assert!(!limiter.check_limit("user2", route, false).await);
     }
+
+    #[tokio::test]
+    async fn test_replication_merges_peer_counts() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 3));
+        let (to_a, mut inbox_a) = mpsc::unbounded_channel();
+        let (to_b, mut inbox_b) = mpsc::unbounded_channel();
+
+        let mut node_a = RateLimiter::new(config.clone())
+            .await
+            .with_replication("node-a", Arc::new(ChannelTransport::new(to_b)));
+        let mut node_b = RateLimiter::new(config)
+            .await
+            .with_replication("node-b", Arc::new(ChannelTransport::new(to_a)));
+
+        let who = "test_user_replicated";
+        let route = "/replicated";
+
+        assert!(node_a.check_limit(who, route, false).await);
+        assert!(node_a.check_limit(who, route, false).await);
+        assert_eq!(node_a.flush_deltas().await, 2, "global and route counters");
+
+        let deltas = inbox_b.recv().await.unwrap();
+        node_b.apply_deltas(&deltas);
+        node_b.apply_deltas(&deltas); // re-delivery must not double count
+
+        assert!(node_b.check_limit(who, route, false).await);
+        assert!(
+            !node_b.check_limit(who, route, false).await,
+            "merged count should reach the limit of 3"
+        );
+
+        node_b.flush_deltas().await;
+        node_a.apply_deltas(&inbox_a.recv().await.unwrap());
+        assert!(!node_a.check_limit(who, route, false).await);
+        assert_eq!(node_a.flush_deltas().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_peer_limited_retry_after_with_expired_local_window() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(1), 2));
+        let (to_b, mut inbox_b) = mpsc::unbounded_channel();
+        let (to_a, _inbox_a) = mpsc::unbounded_channel();
+
+        let mut node_a = RateLimiter::new(config.clone())
+            .await
+            .with_replication("node-a", Arc::new(ChannelTransport::new(to_b)));
+        let mut node_b = RateLimiter::new(config)
+            .await
+            .with_replication("node-b", Arc::new(ChannelTransport::new(to_a)));
+
+        let who = "test_user_peer_limited";
+        assert!(node_b.check_limit(who, "/r", false).await);
+        tokio::time::sleep(StdDuration::from_millis(1100)).await;
+
+        assert!(node_a.check_limit(who, "/r", false).await);
+        assert!(node_a.check_limit(who, "/r", false).await);
+        node_a.flush_deltas().await;
+        node_b.apply_deltas(&inbox_b.recv().await.unwrap());
+
+        match node_b.decide(who, "/r", false).await {
+            Decision::Limited { retry_after, .. } => assert_eq!(
+                retry_after, 1,
+                "the local window has expired, so a full window is assumed"
+            ),
+            other => panic!("expected Limited, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replication_across_key_types() {
+        use std::net::IpAddr;
+
+        let config = LimiterConfig::new(
+            RuleConfig::new(Duration::seconds(10), 2).with_ip_aggregation(IpAggregation::default()),
+        );
+        let (to_b, mut inbox_b) = mpsc::unbounded_channel();
+        let (to_a, _inbox_a) = mpsc::unbounded_channel();
+
+        let mut node_a: RateLimiter<IpAddr> = RateLimiter::new(config.clone())
+            .await
+            .with_replication("node-a", Arc::new(ChannelTransport::new(to_b)));
+        let mut node_b = RateLimiter::new(config)
+            .await
+            .with_replication("node-b", Arc::new(ChannelTransport::new(to_a)));
+
+        let first: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(node_a.check_limit(&first, "/a", false).await);
+        node_a.flush_deltas().await;
+        let deltas = inbox_b.recv().await.unwrap();
+        assert_eq!(deltas[0].who, "192.0.2.0/24");
+        node_b.apply_deltas(&deltas);
+
+        assert!(node_b.check_limit("192.0.2.7", "/a", false).await);
+        assert!(
+            !node_b.check_limit("192.0.2.8", "/a", false).await,
+            "the subnet's count from the IpAddr node is merged"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 10))
+            .add_route_rule("/premium", RuleConfig::new(Duration::seconds(10), 10));
+        let mut limiter = RateLimiter::new(config).await;
+
+        for _ in 0..3 {
+            limiter.check_limit("heavy", "/a", false).await;
+        }
+        limiter.check_limit("heavy", "/b", false).await;
+        limiter.check_limit("light", "/a", false).await;
+        limiter.check_limit("override_only", "/premium", true).await;
+
+        // Let the first GC tick, which fires immediately, complete.
+        tokio::time::sleep(StdDuration::from_millis(50)).await;
+
+        let stats = limiter.stats(2).await;
+        assert_eq!(stats.total_identities, 3);
+        assert_eq!(
+            stats.records_per_route.get(&Scope::Route("/a".to_string())),
+            Some(&2)
+        );
+        assert_eq!(stats.records_per_route.get(&Scope::Global), Some(&2));
+        assert!(stats.estimated_memory > 0);
+        assert_eq!(stats.last_gc.map(|r| r.mode), Some(GcMode::Routine));
+
+        let top: Vec<_> = stats
+            .top_identities
+            .iter()
+            .map(|u| (u.who.as_str(), u.requests))
+            .collect();
+        assert_eq!(top, vec![("heavy", 4), ("light", 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_memory_budget_enforced_on_insert() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 10))
+            .with_max_memory(32 * 1024)
+            .with_gc_interval(3600);
+        let mut limiter: RateLimiter = RateLimiter::new(config).await;
+
+        for i in 0..500 {
+            assert!(
+                limiter
+                    .check_limit(&format!("client-{i}"), "/a", false)
+                    .await
+            );
+        }
+
+        let stats = limiter.stats(0).await;
+        assert!(stats.estimated_memory <= 32 * 1024);
+        assert!(stats.budget_evictions > 0);
+        assert!(limiter.get_record("client-499", "/a").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_query_and_reset() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 2));
+        let mut limiter = RateLimiter::new(config).await;
+
+        assert!(limiter.check_limit("blocked", "/a", false).await);
+        assert!(limiter.check_limit("blocked", "/a", false).await);
+        assert!(!limiter.check_limit("blocked", "/a", false).await);
+        assert!(limiter.check_limit("other", "/a", false).await);
+
+        assert_eq!(limiter.get_record("blocked", "/a").await.unwrap().count, 2);
+        assert_eq!(limiter.get_global_record("blocked").await.unwrap().count, 2);
+        assert!(limiter.get_record("blocked", "/missing").await.is_none());
+
+        assert!(limiter.reset_limit("blocked", "/a").await);
+        assert!(!limiter.reset_limit("blocked", "/a").await);
+        assert!(
+            !limiter.check_limit("blocked", "/a", false).await,
+            "global record still blocks"
+        );
+
+        assert_eq!(limiter.reset_identity("blocked").await, 1);
+        assert!(limiter.check_limit("blocked", "/a", false).await);
+
+        assert_eq!(limiter.reset_route("/a").await, 2);
+        assert!(limiter.get_record("other", "/a").await.is_none());
+        assert!(limiter.get_global_record("other").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_internal_scopes_do_not_alias() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 2));
+        let mut limiter = RateLimiter::new(config).await;
+
+        for _ in 0..2 {
+            assert!(limiter.check_limit("victim", "/a", false).await);
+        }
+        assert!(!limiter.check_limit("victim", "/b", false).await);
+
+        // Keys shaped like the old internal prefixes are ordinary identities and routes.
+        assert!(
+            limiter
+                .check_limit("__global__victim", "__global__", false)
+                .await
+        );
+        assert!(limiter.check_limit("intruder", "__global__", false).await);
+        assert_eq!(limiter.get_global_record("victim").await.unwrap().count, 2);
+        assert_eq!(
+            limiter
+                .get_record("intruder", "__global__")
+                .await
+                .unwrap()
+                .count,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_and_denylist() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 1))
+            .allow_identity("health-checker")
+            .allow_network("10.0.0.0/8".parse().unwrap())
+            .deny_network("10.66.0.0/16".parse().unwrap())
+            .deny_identity("abuser");
+        let mut limiter = RateLimiter::new(config).await;
+
+        for _ in 0..3 {
+            assert_eq!(
+                limiter.decide("health-checker", "/a", false).await,
+                Decision::Allowlisted
+            );
+            assert_eq!(
+                limiter.decide("10.1.1.1", "/a", true).await,
+                Decision::Allowlisted
+            );
+        }
+        assert_eq!(
+            limiter.decide("abuser", "/a", false).await,
+            Decision::Denylisted
+        );
+        assert_eq!(
+            limiter.decide("10.66.1.1", "/a", false).await,
+            Decision::Denylisted,
+            "denylist wins over the allowlist"
+        );
+
+        assert_eq!(
+            limiter.decide("1.2.3.4", "/a", false).await,
+            Decision::Allowed
+        );
+        assert!(matches!(
+            limiter.decide("1.2.3.4", "/a", false).await,
+            Decision::Limited { rule, .. } if rule == "__global__"
+        ));
+        assert!(limiter.get_record("health-checker", "/a").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bans() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 1))
+            .allow_identity("allowlisted")
+            .with_ban_policy(BanPolicy::new(
+                2,
+                Duration::minutes(1),
+                Duration::minutes(5),
+            ));
+        let mut limiter = RateLimiter::new(config).await;
+
+        let until = limiter.ban("manual", Duration::minutes(1)).await;
+        assert_eq!(
+            limiter.decide("manual", "/a", false).await,
+            Decision::Banned { until }
+        );
+        assert!(limiter.unban("manual").await);
+        assert!(limiter.check_limit("manual", "/a", false).await);
+
+        limiter.ban("allowlisted", Duration::minutes(1)).await;
+        assert!(!limiter.check_limit("allowlisted", "/a", false).await);
+
+        assert!(limiter.check_limit("hammer", "/a", false).await);
+        assert!(matches!(
+            limiter.decide("hammer", "/a", false).await,
+            Decision::Limited { .. }
+        ));
+        assert!(limiter.banned_until("hammer").await.is_none());
+        assert!(matches!(
+            limiter.decide("hammer", "/a", false).await,
+            Decision::Limited { .. }
+        ));
+        assert!(matches!(
+            limiter.decide("hammer", "/a", false).await,
+            Decision::Banned { .. }
+        ));
+
+        limiter.reset_identity("hammer").await;
+        assert!(
+            !limiter.check_limit("hammer", "/a", false).await,
+            "bans outlive quota resets"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_penalty_backoff() {
+        let penalty = Penalty::new(
+            Duration::seconds(2),
+            Duration::seconds(3),
+            Duration::minutes(1),
+        );
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(1), 100)).add_route_rule(
+            "/penalized",
+            RuleConfig::new(Duration::seconds(1), 1).with_penalty(penalty),
+        );
+        let mut limiter = RateLimiter::new(config).await;
+        let who = "test_user_penalty";
+
+        assert!(limiter.check_limit(who, "/penalized", true).await);
+        assert_eq!(
+            limiter.decide(who, "/penalized", true).await,
+            Decision::Limited {
+                rule: "/penalized".to_string(),
+                retry_after: 2,
+            }
+        );
+        // Retrying while blocked does not escalate.
+        assert!(!limiter.check_limit(who, "/penalized", true).await);
+        assert_eq!(
+            limiter
+                .get_record(who, "/penalized")
+                .await
+                .unwrap()
+                .violations,
+            1
+        );
+
+        tokio::time::sleep(StdDuration::from_millis(2100)).await;
+        assert!(limiter.check_limit(who, "/penalized", true).await);
+        assert!(
+            matches!(
+                limiter.decide(who, "/penalized", true).await,
+                Decision::Limited { retry_after: 3, .. }
+            ),
+            "second violation doubles the block, capped at max"
+        );
+        assert_eq!(
+            limiter
+                .get_record(who, "/penalized")
+                .await
+                .unwrap()
+                .violations,
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ip_aggregation_per_rule() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 100))
+            .add_route_rule(
+                "/aggregated",
+                RuleConfig::new(Duration::seconds(10), 2)
+                    .with_ip_aggregation(IpAggregation::default()),
+            );
+        let mut limiter = RateLimiter::new(config).await;
+
+        assert!(
+            limiter
+                .check_limit("2001:db8::1", "/aggregated", false)
+                .await
+        );
+        assert!(
+            limiter
+                .check_limit("2001:db8::ffff", "/aggregated", false)
+                .await
+        );
+        assert!(
+            !limiter
+                .check_limit("2001:db8::1:2", "/aggregated", false)
+                .await,
+            "same /64 shares one bucket"
+        );
+        assert!(
+            limiter
+                .check_limit("2001:db8:0:1::1", "/aggregated", false)
+                .await
+        );
+
+        assert!(
+            limiter
+                .get_record("2001:db8::/64", "/aggregated")
+                .await
+                .is_some()
+        );
+        assert_eq!(
+            limiter
+                .get_global_record("2001:db8::1")
+                .await
+                .unwrap()
+                .count,
+            1,
+            "the default rule still counts per address"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_composite_allowlist_ignores_client_supplied_dimensions() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 100))
+            .add_dimension_rule("per_ip", &["ip"], RuleConfig::new(Duration::seconds(10), 1))
+            .allow_network("10.0.0.0/8".parse().unwrap())
+            .allow_identity("trusted-partner")
+            .with_allowlist_dimension("ip");
+        let mut limiter = RateLimiter::new(config).await;
+
+        let key =
+            |ip: &str, api_key: &str| CompositeKey::new().with("ip", ip).with("api_key", api_key);
+        assert_eq!(
+            limiter
+                .decide_composite(&key("203.0.113.5", "10.0.0.1"))
+                .await,
+            Decision::Allowed
+        );
+        assert!(matches!(
+            limiter.decide_composite(&key("203.0.113.5", "trusted-partner")).await,
+            Decision::Limited { rule, .. } if rule == "per_ip"
+        ));
+        assert_eq!(
+            limiter.decide_composite(&key("10.1.2.3", "k1")).await,
+            Decision::Allowlisted
+        );
+    }
+
+    #[tokio::test]
+    async fn test_composite_denials_lead_to_bans() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 100))
+            .add_dimension_rule("per_ip", &["ip"], RuleConfig::new(Duration::seconds(10), 1))
+            .with_ban_policy(BanPolicy::new(
+                2,
+                Duration::minutes(1),
+                Duration::minutes(5),
+            ))
+            .with_ban_dimension("ip");
+        let mut limiter = RateLimiter::new(config).await;
+
+        let key = |api_key: &str| {
+            CompositeKey::new()
+                .with("ip", "203.0.113.5")
+                .with("api_key", api_key)
+        };
+        assert!(limiter.decide_composite(&key("k1")).await.is_allowed());
+        for api_key in ["k2", "k3"] {
+            assert!(matches!(
+                limiter.decide_composite(&key(api_key)).await,
+                Decision::Limited { .. }
+            ));
+        }
+        assert!(limiter.banned_until("203.0.113.5").await.is_some());
+        assert!(
+            limiter.banned_until("k3").await.is_none(),
+            "only the ban dimension is banned"
+        );
+        assert!(matches!(
+            limiter.decide_composite(&key("k4")).await,
+            Decision::Banned { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_composite_keys() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 100))
+            .add_dimension_rule(
+                "per_key_route",
+                &["api_key", "route"],
+                RuleConfig::new(Duration::seconds(10), 2),
+            )
+            .add_dimension_rule("per_ip", &["ip"], RuleConfig::new(Duration::seconds(10), 3))
+            .add_dimension_rule(
+                "per_tenant",
+                &["tenant"],
+                RuleConfig::new(Duration::seconds(10), 100),
+            )
+            .deny_identity("evil-tenant");
+        let mut limiter = RateLimiter::new(config).await;
+
+        let key = |api_key: &str, route: &str| {
+            CompositeKey::new()
+                .with("api_key", api_key)
+                .with("ip", "192.0.2.1")
+                .with("tenant", "acme")
+                .with("route", route)
+        };
+
+        assert!(
+            limiter
+                .decide_composite(&key("k1", "/a"))
+                .await
+                .is_allowed()
+        );
+        assert!(
+            limiter
+                .decide_composite(&key("k1", "/a"))
+                .await
+                .is_allowed()
+        );
+        assert!(matches!(
+            limiter.decide_composite(&key("k1", "/a")).await,
+            Decision::Limited { rule, .. } if rule == "per_key_route"
+        ));
+        assert!(
+            limiter
+                .decide_composite(&key("k2", "/a"))
+                .await
+                .is_allowed()
+        );
+        assert!(matches!(
+            limiter.decide_composite(&key("k3", "/b")).await,
+            Decision::Limited { rule, .. } if rule == "per_ip"
+        ));
+
+        let tenant_only = CompositeKey::new().with("tenant", "acme");
+        assert!(limiter.decide_composite(&tenant_only).await.is_allowed());
+        assert_eq!(
+            limiter
+                .decide_composite(&tenant_only.clone().with("tenant", "evil-tenant"))
+                .await,
+            Decision::Denylisted
+        );
+        assert_eq!(
+            limiter
+                .get_scoped_record("tenant=4:acme", &Scope::Dimension("per_tenant".to_string()))
+                .await
+                .unwrap()
+                .count,
+            4,
+            "denied requests are not counted"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tiers() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 1))
+            .add_route_rule("/login", RuleConfig::new(Duration::seconds(10), 1))
+            .add_tier(
+                "pro",
+                TierConfig::new(RuleConfig::new(Duration::seconds(10), 3))
+                    .add_route_rule("/export", RuleConfig::new(Duration::seconds(10), 2)),
+            )
+            .assign_tier("static_pro", "pro")
+            .with_tier_resolver(|who| who.starts_with("pro_").then(|| "pro".to_string()));
+        let mut limiter = RateLimiter::new(config).await;
+
+        assert!(limiter.check_limit("free_user", "/a", false).await);
+        assert!(!limiter.check_limit("free_user", "/a", false).await);
+
+        for who in ["static_pro", "pro_resolved"] {
+            for _ in 0..3 {
+                assert!(limiter.check_limit(who, "/a", false).await);
+            }
+            assert!(!limiter.check_limit(who, "/a", false).await);
+        }
+
+        assert!(limiter.check_limit("pro_exporter", "/export", true).await);
+        assert!(limiter.check_limit("pro_exporter", "/export", true).await);
+        assert!(!limiter.check_limit("pro_exporter", "/export", true).await);
+
+        assert!(limiter.check_limit("pro_login", "/login", true).await);
+        assert!(
+            !limiter.check_limit("pro_login", "/login", true).await,
+            "base route rules still apply to tiers"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rule_resolver_with_cache() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 1));
+        let mut limiter = RateLimiter::new(config).await.with_rule_resolver(
+            Duration::minutes(1),
+            move |who, _route| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    (who == "vip").then(|| ResolvedRules {
+                        default_rule: Some(RuleConfig::new(Duration::seconds(10), 3)),
+                        route_rule: None,
+                    })
+                }
+            },
+        );
+
+        for _ in 0..3 {
+            assert!(limiter.check_limit("vip", "/a", false).await);
+        }
+        assert!(!limiter.check_limit("vip", "/a", false).await);
+        assert!(limiter.check_limit("regular", "/a", false).await);
+        assert!(!limiter.check_limit("regular", "/a", false).await);
+        assert_eq!(calls.load(Ordering::SeqCst), 2, "one lookup per key");
+
+        limiter.invalidate_resolved_rules("vip");
+        assert!(!limiter.check_limit("vip", "/a", false).await);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_rules_prefetched_outside_the_limiter() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 1));
+        let mut limiter = RateLimiter::new(config).await.with_rule_resolver(
+            Duration::seconds(1),
+            move |_who, _route| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    Some(ResolvedRules {
+                        default_rule: Some(RuleConfig::new(Duration::seconds(10), 2)),
+                        route_rule: None,
+                    })
+                }
+            },
+        );
+        limiter.prefetch_rules();
+
+        let resolver = limiter.rule_resolver().unwrap();
+        resolver.rules_for("vip", "/a").await;
+        assert!(limiter.check_limit("vip", "/a", false).await);
+        assert_eq!(
+            calls.load(Ordering::SeqCst),
+            1,
+            "the limiter used the shared cache"
+        );
+
+        tokio::time::sleep(StdDuration::from_millis(1100)).await;
+        assert!(limiter.check_limit("vip", "/a", false).await);
+        assert_eq!(
+            calls.load(Ordering::SeqCst),
+            1,
+            "expired results are refreshed by the caller, not under the limiter"
+        );
+
+        limiter.invalidate_resolved_rules("vip");
+        assert!(!limiter.check_limit("vip", "/a", false).await);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_permits() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 100))
+            .add_route_rule("/upload", RuleConfig::new(Duration::seconds(10), 4))
+            .add_concurrency_limit("/upload", 2);
+        let mut limiter = RateLimiter::new(config).await;
+        let who = "test_user_uploads";
+
+        let first = limiter.acquire_permit(who, "/upload", false).await.unwrap();
+        let second = limiter.acquire_permit(who, "/upload", false).await.unwrap();
+        assert_eq!(limiter.in_flight(who, "/upload").await, 2);
+        assert_eq!(
+            limiter
+                .acquire_permit(who, "/upload", false)
+                .await
+                .unwrap_err(),
+            Decision::ConcurrencyLimited {
+                rule: "/upload".to_string(),
+                max_in_flight: 2,
+            }
+        );
+        assert!(
+            limiter
+                .acquire_permit("someone_else", "/upload", false)
+                .await
+                .is_ok(),
+            "slots are per identity"
+        );
+
+        drop(first);
+        assert_eq!(limiter.in_flight(who, "/upload").await, 1);
+        let third = limiter.acquire_permit(who, "/upload", false).await.unwrap();
+        drop((second, third));
+        let _fourth = limiter.acquire_permit(who, "/upload", false).await.unwrap();
+        assert!(
+            matches!(
+                limiter.acquire_permit(who, "/upload", false).await,
+                Err(Decision::Limited { .. })
+            ),
+            "the rate rule still applies with free slots"
+        );
+        assert_eq!(limiter.get_record(who, "/upload").await.unwrap().count, 4);
+
+        let unlimited = limiter.acquire_permit(who, "/other", false).await;
+        assert!(unlimited.is_ok());
+        assert_eq!(limiter.in_flight(who, "/other").await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_permits_follow_access_checks_and_record_keys() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 100))
+            .add_route_rule(
+                "/upload",
+                RuleConfig::new(Duration::seconds(10), 100)
+                    .with_ip_aggregation(IpAggregation::default()),
+            )
+            .add_concurrency_limit("/upload", 1)
+            .with_route_mode("/health", RouteMode::Exempt)
+            .add_concurrency_limit("/health", 1);
+        let mut limiter = RateLimiter::new(config).await;
+
+        let held = limiter
+            .acquire_permit("192.0.2.1", "/upload", false)
+            .await
+            .unwrap();
+        assert!(
+            matches!(
+                limiter.acquire_permit("192.0.2.2", "/upload", false).await,
+                Err(Decision::ConcurrencyLimited { .. })
+            ),
+            "slots are shared by the aggregated network"
+        );
+        assert_eq!(limiter.in_flight("192.0.2.9", "/upload").await, 1);
+
+        let until = limiter.ban("192.0.2.1", Duration::minutes(1)).await;
+        assert_eq!(
+            limiter
+                .acquire_permit("192.0.2.1", "/upload", false)
+                .await
+                .unwrap_err(),
+            Decision::Banned { until },
+            "a ban is reported even while permits are held"
+        );
+        drop(held);
+
+        let _first = limiter
+            .acquire_permit("probe", "/health", false)
+            .await
+            .unwrap();
+        let _second = limiter
+            .acquire_permit("probe", "/health", false)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_until_ready() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(1), 1))
+            .add_route_rule("/slow", RuleConfig::new(Duration::seconds(30), 1))
+            .deny_identity("denied");
+        let mut limiter = RateLimiter::new(config).await;
+        let who = "test_user_waiting";
+
+        assert!(limiter.check_limit(who, "/slow", true).await);
+        assert!(
+            matches!(
+                limiter
+                    .until_ready(who, "/slow", true, Some(Duration::seconds(5)))
+                    .await,
+                Err(Decision::Limited { .. })
+            ),
+            "waiting would exceed max_wait"
+        );
+
+        assert!(limiter.check_limit(who, "/a", false).await);
+        let started = Instant::now();
+        assert_eq!(
+            limiter.until_ready(who, "/a", false, None).await,
+            Ok(Decision::Allowed)
+        );
+        assert!(started.elapsed() <= StdDuration::from_millis(1100));
+        assert!(!limiter.check_limit(who, "/a", false).await);
+
+        assert_eq!(
+            limiter.until_ready("denied", "/a", false, None).await,
+            Err(Decision::Denylisted)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refund() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 2))
+            .add_route_rule("/long", RuleConfig::new(Duration::minutes(10), 1));
+        let mut limiter = RateLimiter::new(config).await;
+        let who = "test_user_refund";
+
+        let token = limiter.check_with_token(who, "/a", false).await.unwrap();
+        assert!(limiter.check_limit(who, "/a", false).await);
+        assert!(!limiter.check_limit(who, "/a", false).await);
+
+        assert!(limiter.refund(token).await);
+        assert_eq!(limiter.get_record(who, "/a").await.unwrap().count, 1);
+        assert_eq!(limiter.get_global_record(who).await.unwrap().count, 1);
+        assert!(limiter.check_limit(who, "/a", false).await);
+
+        let token = limiter.check_with_token(who, "/long", true).await.unwrap();
+        assert!(limiter.check_with_token(who, "/long", true).await.is_err());
+        assert!(limiter.refund(token).await);
+        assert!(
+            limiter
+                .get_record(who, "/long")
+                .await
+                .unwrap()
+                .timestamps
+                .is_empty()
+        );
+        assert!(limiter.check_limit(who, "/long", true).await);
+
+        let token = limiter
+            .check_with_token(who, "/unmatched", true)
+            .await
+            .unwrap();
+        assert!(!limiter.refund(token).await);
+    }
+
+    #[tokio::test]
+    async fn test_failures_only_mode() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 100))
+            .add_route_rule(
+                "/login",
+                RuleConfig::new(Duration::minutes(1), 2).failures_only(),
+            );
+        let mut limiter = RateLimiter::new(config).await;
+        let who = "test_user_login";
+
+        for _ in 0..5 {
+            assert!(limiter.check_limit(who, "/login", false).await);
+        }
+        assert!(limiter.get_record(who, "/login").await.is_none());
+
+        assert!(limiter.record_failure(who, "/login").await);
+        assert!(limiter.check_limit(who, "/login", false).await);
+        assert!(limiter.record_success(who, "/login").await);
+
+        assert!(limiter.record_failure(who, "/login").await);
+        assert!(limiter.record_failure(who, "/login").await);
+        assert!(!limiter.check_limit(who, "/login", false).await);
+
+        assert!(
+            !limiter.record_failure(who, "/other").await,
+            "rules counting every request ignore failures"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_response_driven_limits() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 100))
+            .add_route_rule(
+                "/pages",
+                RuleConfig::new(Duration::minutes(1), 2)
+                    .count_responses(ResponseFilter::client_errors()),
+            );
+        let mut limiter = RateLimiter::new(config).await;
+        let scraper = "test_user_scraper";
+        let reader = "test_user_reader";
+
+        for _ in 0..5 {
+            assert!(limiter.check_limit(reader, "/pages", false).await);
+            assert!(!limiter.record_response(reader, "/pages", 200).await);
+            assert!(!limiter.record_response(reader, "/pages", 503).await);
+        }
+        assert!(limiter.check_limit(reader, "/pages", false).await);
+
+        for _ in 0..2 {
+            assert!(limiter.check_limit(scraper, "/pages", false).await);
+            assert!(limiter.record_response(scraper, "/pages", 404).await);
+        }
+        assert!(!limiter.check_limit(scraper, "/pages", false).await);
+        assert!(!limiter.record_response(scraper, "/other", 404).await);
+    }
+
+    #[tokio::test]
+    async fn test_shadow_rules() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::seconds(10), 100))
+            .add_route_rule("/search", RuleConfig::new(Duration::minutes(1), 2).shadow())
+            .with_ban_policy(BanPolicy::new(
+                1,
+                Duration::minutes(1),
+                Duration::minutes(1),
+            ));
+        let mut limiter = RateLimiter::new(config).await;
+        let who = "test_user_shadow";
+
+        for _ in 0..2 {
+            assert_eq!(
+                limiter.decide(who, "/search", false).await,
+                Decision::Allowed
+            );
+        }
+        for _ in 0..3 {
+            let decision = limiter.decide(who, "/search", false).await;
+            assert!(decision.is_allowed());
+            assert!(matches!(
+                decision,
+                Decision::Shadowed { ref rule, retry_after } if rule == "/search" && retry_after > 0
+            ));
+        }
+        assert_eq!(limiter.get_record(who, "/search").await.unwrap().count, 5);
+        assert_eq!(
+            limiter.banned_until(who).await,
+            None,
+            "shadow violations never ban"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_modes() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::minutes(1), 2))
+            .add_route_rule("/replace", RuleConfig::new(Duration::minutes(1), 5))
+            .with_route_mode("/replace", RouteMode::Replace)
+            .with_route_mode("/health", RouteMode::Exempt)
+            .with_route_mode("/internal", RouteMode::Deny)
+            .add_route_rule("/known", RuleConfig::new(Duration::minutes(1), 5))
+            .with_unknown_route_mode(RouteMode::Deny);
+        let mut limiter = RateLimiter::new(config).await;
+        let who = "test_user_modes";
+
+        for _ in 0..4 {
+            assert!(limiter.check_limit(who, "/replace", false).await);
+        }
+        assert!(limiter.get_global_record(who).await.is_none());
+
+        for _ in 0..2 {
+            assert!(limiter.check_limit(who, "/known", false).await);
+        }
+        assert!(!limiter.check_limit(who, "/known", false).await);
+
+        for _ in 0..10 {
+            assert!(limiter.check_limit(who, "/health", true).await);
+        }
+        assert_eq!(
+            limiter.decide(who, "/internal", false).await,
+            Decision::RouteDenied
+        );
+        assert_eq!(
+            limiter.decide(who, "/new-endpoint", true).await,
+            Decision::RouteDenied
+        );
+    }
+
+    #[tokio::test]
+    async fn test_configured_route_modes_apply_in_override_mode() {
+        let config = LimiterConfig::new(RuleConfig::new(Duration::minutes(1), 1))
+            .with_route_mode("/replace", RouteMode::Replace)
+            .add_route_rule("/inherit", RuleConfig::new(Duration::minutes(1), 5))
+            .with_route_mode("/inherit", RouteMode::Inherit);
+        let mut limiter = RateLimiter::new(config).await;
+
+        // Without a route rule, `Replace` falls back to the default rule.
+        assert!(limiter.check_limit("replacer", "/replace", true).await);
+        assert_eq!(
+            limiter.decide("replacer", "/replace", true).await,
+            Decision::Limited {
+                rule: "default".to_string(),
+                retry_after: 60
+            }
+        );
+
+        // An explicit `Inherit` keeps counting the global default rule.
+        assert!(limiter.check_limit("inheritor", "/inherit", true).await);
+        assert!(limiter.get_global_record("inheritor").await.is_some());
+        assert!(!limiter.check_limit("inheritor", "/inherit", true).await);
+    }
+
+    #[tokio::test]
+    async fn test_strict_override() {
+        use std::sync::Mutex;
+
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let seen = reported.clone();
+        let config = LimiterConfig::new(RuleConfig::new(Duration::minutes(1), 100))
+            .add_route_rule("/known", RuleConfig::new(Duration::minutes(1), 5))
+            .with_unmatched_override(UnmatchedRoute::CatchAll(RuleConfig::new(
+                Duration::minutes(1),
+                1,
+            )))
+            .on_unmatched_route(move |route| seen.lock().unwrap().push(route.to_string()));
+        let mut limiter = RateLimiter::new(config).await;
+        let who = "test_user_strict";
+
+        assert!(limiter.check_limit(who, "/known", true).await);
+        assert!(limiter.check_limit(who, "/new", true).await);
+        assert!(matches!(
+            limiter.decide(who, "/new", true).await,
+            Decision::Limited { rule, .. } if rule == "catch_all"
+        ));
+        assert_eq!(*reported.lock().unwrap(), vec!["/new", "/new"]);
+
+        let config = LimiterConfig::new(RuleConfig::new(Duration::minutes(1), 100))
+            .with_unmatched_override(UnmatchedRoute::Deny);
+        let mut limiter = RateLimiter::new(config).await;
+        assert_eq!(
+            limiter.decide(who, "/new", true).await,
+            Decision::RouteDenied
+        );
+        assert!(limiter.check_limit(who, "/new", false).await);
+    }
+
+    #[tokio::test]
+    async fn test_ipv4_mapped_addresses_aggregate_as_ipv4() {
+        use std::net::IpAddr;
+
+        let config = LimiterConfig::new(RuleConfig::new(Duration::minutes(1), 100)).add_route_rule(
+            "/subnet",
+            RuleConfig::new(Duration::minutes(1), 1).with_ip_aggregation(IpAggregation::default()),
+        );
+        let mut limiter = RateLimiter::new(config.clone()).await;
+        assert!(
+            limiter
+                .check_limit("::ffff:192.0.2.1", "/subnet", true)
+                .await
+        );
+        assert!(
+            limiter
+                .check_limit("::ffff:198.51.100.7", "/subnet", true)
+                .await,
+            "different /24s get their own buckets"
+        );
+        assert!(
+            !limiter
+                .check_limit("::ffff:192.0.2.9", "/subnet", true)
+                .await
+        );
+        assert!(
+            limiter
+                .get_record("192.0.2.0/24", "/subnet")
+                .await
+                .is_some()
+        );
+
+        let mut limiter: RateLimiter<IpAddr> = RateLimiter::new(config).await;
+        let first: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        let other: IpAddr = "::ffff:198.51.100.7".parse().unwrap();
+        let neighbour: IpAddr = "::ffff:192.0.2.9".parse().unwrap();
+        assert!(limiter.check_limit(&first, "/subnet", true).await);
+        assert!(limiter.check_limit(&other, "/subnet", true).await);
+        assert!(!limiter.check_limit(&neighbour, "/subnet", true).await);
+    }
+
+    #[tokio::test]
+    async fn test_typed_keys() {
+        use std::net::IpAddr;
+
+        let config = LimiterConfig::new(RuleConfig::new(Duration::minutes(1), 2))
+            .add_route_rule(
+                "/subnet",
+                RuleConfig::new(Duration::minutes(1), 1)
+                    .with_ip_aggregation(IpAggregation::default()),
+            )
+            .allow_network("10.0.0.0/8".parse().unwrap());
+        let mut limiter: RateLimiter<IpAddr> = RateLimiter::new(config).await;
+        let first: IpAddr = "192.0.2.1".parse().unwrap();
+        let neighbour: IpAddr = "192.0.2.2".parse().unwrap();
+        let internal: IpAddr = "10.1.2.3".parse().unwrap();
+
+        assert!(limiter.check_limit(&first, "/a", false).await);
+        assert!(limiter.check_limit(&first, "/a", false).await);
+        assert!(!limiter.check_limit(&first, "/a", false).await);
+        assert_eq!(limiter.get_record(&first, "/a").await.unwrap().count, 2);
+
+        assert!(limiter.check_limit(&neighbour, "/subnet", true).await);
+        assert!(
+            !limiter.check_limit(&first, "/subnet", true).await,
+            "same /24 shares a bucket"
+        );
+        assert_eq!(
+            limiter.decide(&internal, "/a", false).await,
+            Decision::Allowlisted
+        );
+        assert_eq!(limiter.stats(1).await.top_identities[0].who, "192.0.2.1");
+
+        let config = LimiterConfig::new(RuleConfig::new(Duration::minutes(1), 1));
+        let mut limiter: RateLimiter<u64> = RateLimiter::new(config).await;
+        assert!(limiter.check_limit(&42, "/a", false).await);
+        assert!(!limiter.check_limit(&42, "/a", false).await);
+        assert!(limiter.check_limit(&43, "/a", false).await);
+    }
 }
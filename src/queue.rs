@@ -0,0 +1,154 @@
+/* src/queue.rs */
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Mutex as TurnLock, OwnedMutexGuard};
+
+struct KeyQueue {
+    turn: Arc<TurnLock<()>>,
+    waiting: usize,
+}
+
+/// FIFO queues of `until_ready` callers, one per `(who, route)`.
+///
+/// Only the head of a queue polls the rules, so waiters are admitted in
+/// arrival order instead of racing each other at every window reset. The
+/// turn lock is a tokio mutex, which already hands out locks in FIFO order.
+pub(crate) struct WaitQueues {
+    queues: Mutex<HashMap<(String, String), KeyQueue>>,
+    max_len: usize,
+}
+
+impl WaitQueues {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            queues: Mutex::new(HashMap::new()),
+            max_len,
+        }
+    }
+
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    /// Join the queue for `(who, route)` and wait to reach its head.
+    /// Returns `None` without waiting if the queue is full.
+    pub async fn wait_turn(self: &Arc<Self>, who: &str, route: &str) -> Option<Turn> {
+        let key = (who.to_string(), route.to_string());
+        let turn = {
+            let mut queues = self.queues.lock().unwrap_or_else(|e| e.into_inner());
+            // Check the bound first so rejected callers never leave an entry behind.
+            let waiting = queues.get(&key).map_or(0, |queue| queue.waiting);
+            if waiting >= self.max_len {
+                return None;
+            }
+            let queue = queues.entry(key.clone()).or_insert_with(|| KeyQueue {
+                turn: Arc::new(TurnLock::new(())),
+                waiting: 0,
+            });
+            queue.waiting += 1;
+            queue.turn.clone()
+        };
+
+        // Created before awaiting so a cancelled waiter still leaves the queue.
+        let membership = Membership {
+            queues: self.clone(),
+            key,
+        };
+        let guard = turn.lock_owned().await;
+        Some(Turn {
+            _guard: guard,
+            _membership: membership,
+        })
+    }
+}
+
+struct Membership {
+    queues: Arc<WaitQueues>,
+    key: (String, String),
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        let mut queues = self.queues.queues.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(queue) = queues.get_mut(&self.key) {
+            queue.waiting = queue.waiting.saturating_sub(1);
+            if queue.waiting == 0 {
+                queues.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Being at the head of a wait queue. Dropping it lets the next waiter in.
+pub(crate) struct Turn {
+    _guard: OwnedMutexGuard<()>,
+    _membership: Membership,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fifo_order_and_bound() {
+        let queues = Arc::new(WaitQueues::new(3));
+        let head = queues.wait_turn("who", "/route").await.unwrap();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let mut waiters = Vec::new();
+        for i in 0..2 {
+            let (queues, order) = (queues.clone(), order.clone());
+            waiters.push(tokio::spawn(async move {
+                let _turn = queues.wait_turn("who", "/route").await.unwrap();
+                order.lock().unwrap().push(i);
+            }));
+            // Let the waiter join before the next one arrives.
+            tokio::task::yield_now().await;
+        }
+
+        assert!(
+            queues.wait_turn("who", "/route").await.is_none(),
+            "queue is full"
+        );
+        assert!(queues.wait_turn("other", "/route").await.is_some());
+
+        drop(head);
+        for waiter in waiters {
+            waiter.await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), vec![0, 1]);
+        assert!(queues.queues.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_zero_length_queue_keeps_no_entries() {
+        let queues = Arc::new(WaitQueues::new(0));
+        for i in 0..100 {
+            assert!(
+                queues
+                    .wait_turn(&format!("who-{i}"), "/route")
+                    .await
+                    .is_none()
+            );
+        }
+        assert!(queues.queues.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_waiter_leaves_queue() {
+        let queues = Arc::new(WaitQueues::new(2));
+        let head = queues.wait_turn("who", "/route").await.unwrap();
+
+        let waiter = {
+            let queues = queues.clone();
+            tokio::spawn(async move { queues.wait_turn("who", "/route").await.is_some() })
+        };
+        tokio::task::yield_now().await;
+        waiter.abort();
+        let _ = waiter.await;
+
+        drop(head);
+        assert!(queues.queues.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,206 @@
+/* src/replication.rs */
+
+use crate::access::IpAggregation;
+use crate::key::LimitKey;
+use crate::store::Store;
+use crate::types::{Scope, ScopeRef, current_timestamp};
+use std::borrow::{Borrow, Cow};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// A G-counter delta: the total one node has counted for a `(who, scope)` window.
+///
+/// Deltas carry state rather than increments, so applying the same delta twice
+/// or out of order is harmless: counts from the same node and window merge by max.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CounterDelta {
+    pub node_id: String,
+    pub who: String,
+    pub scope: Scope,
+    pub window_start: u64,
+    pub count: u32,
+}
+
+/// Carries counter deltas from this node to its peers.
+pub trait DeltaTransport: Send + Sync {
+    fn send(&self, deltas: Vec<CounterDelta>);
+}
+
+/// In-process transport backed by a tokio channel.
+pub struct ChannelTransport {
+    sender: UnboundedSender<Vec<CounterDelta>>,
+}
+
+impl ChannelTransport {
+    pub fn new(sender: UnboundedSender<Vec<CounterDelta>>) -> Self {
+        Self { sender }
+    }
+}
+
+impl DeltaTransport for ChannelTransport {
+    fn send(&self, deltas: Vec<CounterDelta>) {
+        // A closed receiver only means the peer went away.
+        let _ = self.sender.send(deltas);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RemoteCount {
+    window_start: u64,
+    count: u32,
+}
+
+/// The name peers know `who` by. Aggregated IP keys are named by their
+/// network, e.g. `192.0.2.0/24`, whether the key is a string or an `IpAddr`.
+fn wire_name<'a, K: ?Sized + LimitKey>(
+    who: &'a K,
+    aggregation: Option<&IpAggregation>,
+) -> Cow<'a, str> {
+    match (aggregation, who.ip()) {
+        (Some(aggregation), Some(ip)) => Cow::Owned(aggregation.network(ip).to_string()),
+        _ => who.name(),
+    }
+}
+
+/// How to read a changed record when flushing it.
+#[derive(Debug, Clone, Copy)]
+struct DirtyWindow {
+    is_short_interval: bool,
+    window_size: u64,
+    aggregation: Option<IpAggregation>,
+}
+
+/// Peers exchange identities by name, so nodes may use different key types.
+pub(crate) struct Replicator<K: ?Sized + LimitKey> {
+    node_id: String,
+    transport: Arc<dyn DeltaTransport>,
+    dirty: HashMap<(K::Owned, Scope), DirtyWindow>,
+    remote: HashMap<(String, Scope), HashMap<String, RemoteCount>>,
+}
+
+impl<K: ?Sized + LimitKey> Replicator<K> {
+    pub fn new(node_id: String, transport: Arc<dyn DeltaTransport>) -> Self {
+        Self {
+            node_id,
+            transport,
+            dirty: HashMap::new(),
+            remote: HashMap::new(),
+        }
+    }
+
+    pub fn mark_dirty(
+        &mut self,
+        who: &K,
+        scope: ScopeRef<'_>,
+        is_short_interval: bool,
+        window_size: u64,
+        aggregation: Option<&IpAggregation>,
+    ) {
+        self.dirty.insert(
+            (who.to_owned(), scope.to_scope()),
+            DirtyWindow {
+                is_short_interval,
+                window_size,
+                aggregation: aggregation.copied(),
+            },
+        );
+    }
+
+    /// Sum of the live counts peers reported for this key.
+    pub fn remote_count(
+        &self,
+        who: &K,
+        scope: ScopeRef<'_>,
+        window_size: u64,
+        aggregation: Option<&IpAggregation>,
+    ) -> u32 {
+        if self.remote.is_empty() {
+            return 0;
+        }
+        let now = current_timestamp();
+        self.remote
+            .get(&(wire_name(who, aggregation).into_owned(), scope.to_scope()))
+            .map(|nodes| {
+                nodes
+                    .values()
+                    .filter(|c| now.saturating_sub(c.window_start) < window_size)
+                    .map(|c| c.count)
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    pub fn flush(&mut self, records: &Store<K>) -> usize {
+        let mut deltas = Vec::with_capacity(self.dirty.len());
+
+        for ((who, scope), window) in self.dirty.drain() {
+            let DirtyWindow {
+                is_short_interval,
+                window_size,
+                aggregation,
+            } = window;
+            let key: &K = who.borrow();
+            let Some(record) = records.get(key, scope.as_scope_ref()) else {
+                continue;
+            };
+            let window_start = if is_short_interval {
+                record.window_start
+            } else {
+                *record.timestamps.last().unwrap_or(&record.window_start)
+            };
+            deltas.push(CounterDelta {
+                node_id: self.node_id.clone(),
+                who: wire_name(key, aggregation.as_ref()).into_owned(),
+                scope,
+                window_start,
+                count: record.current_count(is_short_interval, window_size),
+            });
+        }
+
+        let sent = deltas.len();
+        if sent > 0 {
+            self.transport.send(deltas);
+        }
+        sent
+    }
+
+    /// Drop peer counts for every key matching `predicate`.
+    pub fn forget(&mut self, mut predicate: impl FnMut(&str, &Scope) -> bool) {
+        self.remote.retain(|(who, scope), _| !predicate(who, scope));
+        self.dirty
+            .retain(|(who, scope), _| !predicate(&who.borrow().name(), scope));
+    }
+
+    pub fn apply(&mut self, deltas: &[CounterDelta], max_age: u64) {
+        for delta in deltas {
+            if delta.node_id == self.node_id {
+                continue;
+            }
+            let nodes = self
+                .remote
+                .entry((delta.who.clone(), delta.scope.clone()))
+                .or_default();
+            let incoming = RemoteCount {
+                window_start: delta.window_start,
+                count: delta.count,
+            };
+            nodes
+                .entry(delta.node_id.clone())
+                .and_modify(|current| {
+                    if incoming.window_start > current.window_start {
+                        *current = incoming;
+                    } else if incoming.window_start == current.window_start {
+                        current.count = current.count.max(incoming.count);
+                    }
+                })
+                .or_insert(incoming);
+        }
+
+        let now = current_timestamp();
+        self.remote.retain(|_key, nodes| {
+            nodes.retain(|_node, c| now.saturating_sub(c.window_start) <= max_age);
+            !nodes.is_empty()
+        });
+    }
+}
@@ -0,0 +1,91 @@
+/* src/resolver.rs */
+
+use crate::types::RuleConfig;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::time::{Duration as TokioDuration, Instant};
+
+/// Rules returned by a resolver. A `None` field keeps the configured rule.
+#[derive(Debug, Clone, Default)]
+pub struct ResolvedRules {
+    /// Replaces the default rule, which also drives the global limit.
+    pub default_rule: Option<RuleConfig>,
+    /// Replaces the rule for the requested route.
+    pub route_rule: Option<RuleConfig>,
+}
+
+type ResolveFuture = Pin<Box<dyn Future<Output = Option<ResolvedRules>> + Send>>;
+type ResolveFn = dyn Fn(String, String) -> ResolveFuture + Send + Sync;
+
+struct Cache {
+    entries: HashMap<(String, String), (Instant, Option<ResolvedRules>)>,
+    prune_at: usize,
+}
+
+/// Caches an async `(who, route)` rule lookup for a fixed TTL.
+///
+/// Clones share the cache, so a lookup can run without holding the lock on
+/// the limiter and still be seen by it.
+#[derive(Clone)]
+pub(crate) struct RuleResolver {
+    resolve: Arc<ResolveFn>,
+    ttl: TokioDuration,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl RuleResolver {
+    pub fn new<F, Fut>(ttl: TokioDuration, resolve: F) -> Self
+    where
+        F: Fn(String, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<ResolvedRules>> + Send + 'static,
+    {
+        Self {
+            resolve: Arc::new(move |who, route| Box::pin(resolve(who, route))),
+            ttl,
+            cache: Arc::new(Mutex::new(Cache {
+                entries: HashMap::new(),
+                prune_at: 1024,
+            })),
+        }
+    }
+
+    fn cache(&self) -> MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The cached result for `(who, route)`, even if it has expired.
+    pub fn cached(&self, who: &str, route: &str) -> Option<Option<ResolvedRules>> {
+        let key = (who.to_string(), route.to_string());
+        self.cache()
+            .entries
+            .get(&key)
+            .map(|(_, rules)| rules.clone())
+    }
+
+    pub async fn rules_for(&self, who: &str, route: &str) -> Option<ResolvedRules> {
+        let key = (who.to_string(), route.to_string());
+        let now = Instant::now();
+        if let Some((expires_at, rules)) = self.cache().entries.get(&key)
+            && *expires_at > now
+        {
+            return rules.clone();
+        }
+
+        let rules = (self.resolve)(key.0.clone(), key.1.clone()).await;
+        let mut cache = self.cache();
+        cache.entries.insert(key, (now + self.ttl, rules.clone()));
+
+        // Amortise pruning so the cache cannot grow without bound.
+        if cache.entries.len() >= cache.prune_at {
+            cache.entries.retain(|_, (expires_at, _)| *expires_at > now);
+            cache.prune_at = (cache.entries.len() * 2).max(1024);
+        }
+        rules
+    }
+
+    pub fn invalidate(&self, who: &str) {
+        self.cache().entries.retain(|(w, _), _| w != who);
+    }
+}
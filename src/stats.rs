@@ -0,0 +1,101 @@
+/* src/stats.rs */
+
+use crate::key::LimitKey;
+use crate::store::{self, ScopeId, Store};
+use crate::types::Scope;
+use std::collections::HashMap;
+
+/// Which cleanup strategy a garbage collection pass used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcMode {
+    /// Only records idle for longer than any rule window were dropped.
+    Routine,
+    /// Memory was over budget, so the oldest records were evicted as well.
+    Aggressive,
+}
+
+impl GcMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GcMode::Routine => "routine",
+            GcMode::Aggressive => "aggressive",
+        }
+    }
+}
+
+/// Outcome of the most recent garbage collection pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcReport {
+    pub finished_at: u64,
+    pub mode: GcMode,
+    pub removed: usize,
+    pub memory_before: usize,
+    pub memory_after: usize,
+}
+
+/// Requests and memory attributed to a single identity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentityUsage {
+    pub who: String,
+    pub requests: u64,
+    pub memory: usize,
+}
+
+/// Snapshot of what the limiter is currently tracking.
+#[derive(Debug, Clone)]
+pub struct LimiterStats {
+    pub total_identities: usize,
+    /// Records per scope: the global limit, each route and each dimension rule.
+    pub records_per_route: HashMap<Scope, usize>,
+    /// Bytes held by records, keys, their tables and interned names.
+    pub estimated_memory: usize,
+    /// Records evicted when a new one would not have fit in `max_memory`.
+    pub budget_evictions: u64,
+    pub last_gc: Option<GcReport>,
+    /// Identities with the most counted requests, heaviest first.
+    pub top_identities: Vec<IdentityUsage>,
+}
+
+pub(crate) fn collect<K: ?Sized + LimitKey>(
+    records: &Store<K>,
+    last_gc: Option<GcReport>,
+    top_n: usize,
+) -> LimiterStats {
+    let mut records_per_scope: HashMap<ScopeId, usize> = HashMap::new();
+    let mut identities: Vec<IdentityUsage> = Vec::new();
+
+    for (who, scopes) in records.identities() {
+        let mut usage = IdentityUsage {
+            who: who.to_string(),
+            requests: 0,
+            memory: store::identity_size(who, scopes),
+        };
+        for (scope, record) in scopes.iter() {
+            *records_per_scope.entry(*scope).or_default() += 1;
+            // The global record counts the same requests as the route records
+            // again, so it is left out.
+            if *scope != ScopeId::Global {
+                usage.requests += record.count as u64;
+            }
+        }
+        identities.push(usage);
+    }
+
+    let records_per_route = records_per_scope
+        .into_iter()
+        .map(|(id, count)| (records.scope(id).to_scope(), count))
+        .collect();
+    let total_identities = identities.len();
+    let mut top_identities = identities;
+    top_identities.sort_by(|a, b| b.requests.cmp(&a.requests).then_with(|| a.who.cmp(&b.who)));
+    top_identities.truncate(top_n);
+
+    LimiterStats {
+        total_identities,
+        records_per_route,
+        estimated_memory: records.memory_usage(),
+        budget_evictions: records.evicted(),
+        last_gc,
+        top_identities,
+    }
+}
@@ -0,0 +1,610 @@
+/* src/store.rs */
+
+use crate::key::LimitKey;
+use crate::telemetry;
+use crate::types::{RequestRecord, ScopeRef, current_timestamp};
+use std::borrow::Borrow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::mem::size_of;
+use std::ops::{Deref, DerefMut};
+
+/// Bytes the allocator hands out for a request of `bytes`: a malloc-style
+/// chunk with an 8 byte header, rounded up to 16 bytes.
+pub(crate) fn allocation_size(bytes: usize) -> usize {
+    if bytes == 0 {
+        return 0;
+    }
+    (bytes + 8).next_multiple_of(16).max(32)
+}
+
+/// Bytes allocated by a `HashMap` with `capacity` whose entries are `T`,
+/// following the hashbrown layout: one slot and one control byte per bucket,
+/// plus a trailing group of control bytes.
+pub(crate) fn table_size<T>(capacity: usize) -> usize {
+    if capacity == 0 {
+        return 0;
+    }
+    let buckets = if capacity < 8 {
+        capacity + 1
+    } else {
+        capacity / 7 * 8
+    };
+    allocation_size(buckets * (size_of::<T>() + 1) + 16)
+}
+
+/// A route or dimension name interned into a compact id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct NameId(u32);
+
+/// The compact form of a `Scope` that records are stored under.
+///
+/// Ids are only meaningful while the store's lock is held: a name is released
+/// once its last record goes away and its id may be reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum ScopeId {
+    Global,
+    Route(NameId),
+    Dimension(NameId),
+}
+
+/// Route and dimension names in use, each stored once and reference counted
+/// by the records that point at it.
+#[derive(Debug, Default)]
+struct Interner {
+    ids: HashMap<Box<str>, NameId>,
+    /// Name and record count per id; `None` for free slots.
+    names: Vec<Option<(Box<str>, usize)>>,
+    free: Vec<u32>,
+    /// Bytes allocated for the live names.
+    heap: usize,
+}
+
+impl Interner {
+    fn get(&self, name: &str) -> Option<NameId> {
+        self.ids.get(name).copied()
+    }
+
+    fn intern(&mut self, name: &str) -> NameId {
+        if let Some(id) = self.get(name) {
+            return id;
+        }
+        let slot = Some((Box::from(name), 0));
+        let id = match self.free.pop() {
+            Some(index) => {
+                self.names[index as usize] = slot;
+                NameId(index)
+            }
+            None => {
+                self.names.push(slot);
+                NameId(self.names.len() as u32 - 1)
+            }
+        };
+        self.ids.insert(Box::from(name), id);
+        // Each live name is held once in `ids` and once in `names`.
+        self.heap += 2 * allocation_size(name.len());
+        id
+    }
+
+    fn name(&self, id: NameId) -> &str {
+        match &self.names[id.0 as usize] {
+            Some((name, _)) => name,
+            None => unreachable!("interned name {} was released", id.0),
+        }
+    }
+
+    fn acquire(&mut self, id: NameId) {
+        if let Some((_, refs)) = &mut self.names[id.0 as usize] {
+            *refs += 1;
+        }
+    }
+
+    fn release(&mut self, id: NameId) {
+        let slot = &mut self.names[id.0 as usize];
+        if let Some((name, refs)) = slot {
+            *refs -= 1;
+            if *refs == 0 {
+                self.heap -= 2 * allocation_size(name.len());
+                self.ids.remove(&**name);
+                *slot = None;
+                self.free.push(id.0);
+            }
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.heap
+            + table_size::<(Box<str>, NameId)>(self.ids.capacity())
+            + allocation_size(self.names.capacity() * size_of::<Option<(Box<str>, usize)>>())
+            + allocation_size(self.free.capacity() * size_of::<u32>())
+    }
+}
+
+/// An identity's place in the eviction order: the last activity of its
+/// least recently active record when it was queued, with ties broken by
+/// queueing order.
+struct Queued<O> {
+    activity: u64,
+    seq: u64,
+    who: O,
+}
+
+impl<O> Queued<O> {
+    fn order(&self) -> (u64, u64) {
+        (self.activity, self.seq)
+    }
+}
+
+impl<O> PartialEq for Queued<O> {
+    fn eq(&self, other: &Self) -> bool {
+        self.order() == other.order()
+    }
+}
+
+impl<O> Eq for Queued<O> {}
+
+impl<O> PartialOrd for Queued<O> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<O> Ord for Queued<O> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.order().cmp(&other.order())
+    }
+}
+
+/// Every tracked record, keyed by identity and then by scope.
+///
+/// Memory is tracked as records are inserted, grown and removed, and new
+/// records are only admitted within `budget` bytes.
+pub(crate) struct Store<K: ?Sized + LimitKey> {
+    records: HashMap<K::Owned, HashMap<ScopeId, RequestRecord>>,
+    names: Interner,
+    /// Bytes held by each identity's key, scope table and record buffers.
+    bytes: usize,
+    budget: usize,
+    /// Records evicted to admit new ones within the budget.
+    evicted: u64,
+    /// Identities whose oldest record was least recently active first, one
+    /// entry per identity. Entries are only added when an identity is
+    /// created, so requests do no heap work: eviction skips identities that
+    /// are gone and requeues those that have been active since.
+    eviction_queue: BinaryHeap<Reverse<Queued<K::Owned>>>,
+    /// Heap bytes of the keys held by `eviction_queue`.
+    queue_bytes: usize,
+    next_seq: u64,
+}
+
+/// A record borrowed from the store that updates the store's byte count when
+/// it is released, e.g. after `add_request` grew its timestamps.
+pub(crate) struct RecordMut<'a> {
+    record: &'a mut RequestRecord,
+    bytes: &'a mut usize,
+    heap: usize,
+}
+
+impl Deref for RecordMut<'_> {
+    type Target = RequestRecord;
+
+    fn deref(&self) -> &RequestRecord {
+        self.record
+    }
+}
+
+impl DerefMut for RecordMut<'_> {
+    fn deref_mut(&mut self) -> &mut RequestRecord {
+        self.record
+    }
+}
+
+impl Drop for RecordMut<'_> {
+    fn drop(&mut self) {
+        *self.bytes = *self.bytes - self.heap + self.record.heap_usage();
+    }
+}
+
+impl<'a> RecordMut<'a> {
+    fn new(record: &'a mut RequestRecord, bytes: &'a mut usize) -> Self {
+        let heap = record.heap_usage();
+        Self {
+            record,
+            bytes,
+            heap,
+        }
+    }
+}
+
+impl<K: ?Sized + LimitKey> Store<K> {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            records: HashMap::new(),
+            names: Interner::default(),
+            bytes: 0,
+            budget,
+            evicted: 0,
+            eviction_queue: BinaryHeap::new(),
+            queue_bytes: 0,
+            next_seq: 0,
+        }
+    }
+
+    /// The id `scope` is stored under, if any record uses it.
+    pub fn id(&self, scope: ScopeRef<'_>) -> Option<ScopeId> {
+        match scope {
+            ScopeRef::Global => Some(ScopeId::Global),
+            ScopeRef::Route(name) => self.names.get(name).map(ScopeId::Route),
+            ScopeRef::Dimension(name) => self.names.get(name).map(ScopeId::Dimension),
+        }
+    }
+
+    pub fn scope(&self, id: ScopeId) -> ScopeRef<'_> {
+        match id {
+            ScopeId::Global => ScopeRef::Global,
+            ScopeId::Route(name) => ScopeRef::Route(self.names.name(name)),
+            ScopeId::Dimension(name) => ScopeRef::Dimension(self.names.name(name)),
+        }
+    }
+
+    pub fn get(&self, who: &K, scope: ScopeRef<'_>) -> Option<&RequestRecord> {
+        let id = self.id(scope)?;
+        self.records.get(who)?.get(&id)
+    }
+
+    pub fn get_mut(&mut self, who: &K, scope: ScopeRef<'_>) -> Option<RecordMut<'_>> {
+        let id = self.id(scope)?;
+        let record = self.records.get_mut(who)?.get_mut(&id)?;
+        Some(RecordMut::new(record, &mut self.bytes))
+    }
+
+    /// The record for `who` in `scope`, created with `new` if there is none.
+    ///
+    /// If creating the record takes the store over budget, the least recently
+    /// active other records are evicted until it fits again.
+    pub fn get_or_insert(
+        &mut self,
+        who: &K,
+        scope: ScopeRef<'_>,
+        new: impl FnOnce() -> RequestRecord,
+    ) -> RecordMut<'_> {
+        let id = match scope {
+            ScopeRef::Global => ScopeId::Global,
+            ScopeRef::Route(name) => ScopeId::Route(self.names.intern(name)),
+            ScopeRef::Dimension(name) => ScopeId::Dimension(self.names.intern(name)),
+        };
+        if !self.records.contains_key(who) {
+            self.records.insert(who.to_owned(), HashMap::new());
+            self.bytes += who.memory_usage();
+            self.enqueue(who.to_owned(), current_timestamp());
+        }
+        let scopes = self
+            .records
+            .get_mut(who)
+            .expect("identity was just inserted");
+        if !scopes.contains_key(&id) {
+            let capacity = scopes.capacity();
+            let record = new();
+            self.bytes += record.heap_usage();
+            scopes.insert(id, record);
+            self.bytes += scope_table_size(scopes.capacity()) - scope_table_size(capacity);
+            if let ScopeId::Route(name) | ScopeId::Dimension(name) = id {
+                self.names.acquire(name);
+            }
+
+            if self.memory_usage() > self.budget {
+                let removed = self.evict_to(self.budget, Some((who, id)));
+                self.evicted += removed as u64;
+                telemetry::record_budget_eviction(removed, self.memory_usage());
+            }
+        }
+        let record = self
+            .records
+            .get_mut(who)
+            .and_then(|scopes| scopes.get_mut(&id))
+            .expect("record was just inserted");
+        RecordMut::new(record, &mut self.bytes)
+    }
+
+    /// Remove one record, dropping the identity once it has none left.
+    pub fn remove(&mut self, who: &K, id: ScopeId) -> Option<RequestRecord> {
+        let scopes = self.records.get_mut(who)?;
+        let removed = scopes.remove(&id)?;
+        self.bytes -= removed.heap_usage();
+        if scopes.is_empty() {
+            self.bytes -= who.memory_usage() + scope_table_size(scopes.capacity());
+            self.records.remove(who);
+        }
+        self.release(id);
+        Some(removed)
+    }
+
+    /// Remove every record of `who`. Returns how many there were.
+    pub fn remove_identity(&mut self, who: &K) -> usize {
+        let Some(scopes) = self.records.remove(who) else {
+            return 0;
+        };
+        self.bytes -= identity_size(who, &scopes);
+        for id in scopes.keys() {
+            self.release(*id);
+        }
+        scopes.len()
+    }
+
+    /// Keep only the records `keep` returns true for. Returns how many were removed.
+    pub fn retain(&mut self, mut keep: impl FnMut(&K, ScopeId, &RequestRecord) -> bool) -> usize {
+        let mut released = Vec::new();
+        let bytes = &mut self.bytes;
+        self.records.retain(|who, scopes| {
+            let who: &K = who.borrow();
+            scopes.retain(|id, record| {
+                let kept = keep(who, *id, record);
+                if !kept {
+                    *bytes -= record.heap_usage();
+                    released.push(*id);
+                }
+                kept
+            });
+            if scopes.is_empty() {
+                *bytes -= who.memory_usage() + scope_table_size(scopes.capacity());
+            }
+            !scopes.is_empty()
+        });
+        for id in &released {
+            self.release(*id);
+        }
+        self.compact_queue();
+        released.len()
+    }
+
+    pub fn identities(&self) -> impl Iterator<Item = (&K, &HashMap<ScopeId, RequestRecord>)> {
+        self.records
+            .iter()
+            .map(|(who, scopes)| (who.borrow(), scopes))
+    }
+
+    #[cfg(test)]
+    pub fn clear(&mut self) {
+        self.records = HashMap::new();
+        self.names = Interner::default();
+        self.bytes = 0;
+        self.eviction_queue = BinaryHeap::new();
+        self.queue_bytes = 0;
+    }
+
+    /// Bytes allocated for the records, their keys, the tables holding them
+    /// and the interned names. Kept up to date on every change.
+    pub fn memory_usage(&self) -> usize {
+        table_size::<(K::Owned, HashMap<ScopeId, RequestRecord>)>(self.records.capacity())
+            + self.bytes
+            + self.names.memory_usage()
+            + self.queue_memory()
+    }
+
+    fn queue_memory(&self) -> usize {
+        allocation_size(self.eviction_queue.capacity() * size_of::<Queued<K::Owned>>())
+            + self.queue_bytes
+    }
+
+    fn enqueue(&mut self, who: K::Owned, activity: u64) {
+        self.queue_bytes += who.borrow().memory_usage();
+        self.eviction_queue.push(Reverse(Queued {
+            activity,
+            seq: self.next_seq,
+            who,
+        }));
+        self.next_seq += 1;
+    }
+
+    /// Rebuild the eviction queue once stale entries make up most of it.
+    fn compact_queue(&mut self) {
+        if self.eviction_queue.capacity() <= 2 * self.records.len() + 64 {
+            return;
+        }
+        let mut live: Vec<(K::Owned, u64)> = self
+            .records
+            .iter()
+            .filter_map(|(who, scopes)| {
+                let who: &K = who.borrow();
+                Some((who.to_owned(), oldest(scopes, None)?.1))
+            })
+            .collect();
+        live.sort_by_key(|&(_, activity)| activity);
+        self.eviction_queue = BinaryHeap::with_capacity(live.len());
+        self.queue_bytes = 0;
+        for (who, activity) in live {
+            self.enqueue(who, activity);
+        }
+    }
+
+    /// Records evicted so far to admit new ones within the budget.
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    /// Remove the least recently active records, except `keep`, until memory
+    /// is at most `target`. Each removal costs a few heap operations, so
+    /// evicting a handful of records never walks the whole store.
+    /// Returns how many were removed.
+    pub fn evict_to(&mut self, target: usize, keep: Option<(&K, ScopeId)>) -> usize {
+        let mut removed = 0;
+        let mut kept = None;
+        while self.memory_usage() > target {
+            let Some(Reverse(queued)) = self.eviction_queue.pop() else {
+                break;
+            };
+            let who: &K = queued.who.borrow();
+            self.queue_bytes -= who.memory_usage();
+            let Some(scopes) = self.records.get(who) else {
+                continue;
+            };
+            let skip = keep.filter(|&(kept, _)| kept == who).map(|(_, id)| id);
+            let Some((id, activity)) = oldest(scopes, skip) else {
+                // Only the record being admitted is left.
+                kept = Some(queued);
+                continue;
+            };
+            if activity > queued.activity {
+                self.enqueue(queued.who, activity);
+                continue;
+            }
+            self.remove(who, id);
+            removed += 1;
+            match self.records.get(who).map(|scopes| oldest(scopes, skip)) {
+                Some(Some((_, activity))) => self.enqueue(queued.who, activity),
+                Some(None) => kept = Some(queued),
+                None => {}
+            }
+        }
+        if let Some(queued) = kept {
+            self.enqueue(queued.who, queued.activity);
+        }
+        self.compact_queue();
+        removed
+    }
+
+    /// Release identity table capacity left over from a traffic spike.
+    pub fn shrink(&mut self) {
+        if self.records.len() * 4 < self.records.capacity() {
+            self.records.shrink_to_fit();
+        }
+    }
+
+    fn release(&mut self, id: ScopeId) {
+        if let ScopeId::Route(name) | ScopeId::Dimension(name) = id {
+            self.names.release(name);
+        }
+    }
+}
+
+/// The least recently active record in `scopes`, other than `skip`.
+fn oldest(
+    scopes: &HashMap<ScopeId, RequestRecord>,
+    skip: Option<ScopeId>,
+) -> Option<(ScopeId, u64)> {
+    scopes
+        .iter()
+        .filter(|&(id, _)| Some(*id) != skip)
+        .map(|(id, record)| (*id, record.last_activity()))
+        .min_by_key(|&(_, activity)| activity)
+}
+
+fn scope_table_size(capacity: usize) -> usize {
+    table_size::<(ScopeId, RequestRecord)>(capacity)
+}
+
+/// Bytes held by an identity: its key, its scope table with the records in
+/// it and their buffers. Route names are shared via the interner.
+pub(crate) fn identity_size<K: ?Sized + LimitKey>(
+    who: &K,
+    scopes: &HashMap<ScopeId, RequestRecord>,
+) -> usize {
+    who.memory_usage()
+        + scope_table_size(scopes.capacity())
+        + scopes
+            .values()
+            .map(RequestRecord::heap_usage)
+            .sum::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names_are_shared_and_released() {
+        let mut store: Store<str> = Store::new(usize::MAX);
+        for who in ["a", "b", "c"] {
+            store.get_or_insert(who, ScopeRef::Route("/login"), || RequestRecord::new(true));
+            store.get_or_insert(who, ScopeRef::Global, || RequestRecord::new(true));
+        }
+        assert_eq!(store.names.ids.len(), 1);
+        let login = store.id(ScopeRef::Route("/login")).unwrap();
+        assert_eq!(store.scope(login), ScopeRef::Route("/login"));
+        assert!(store.id(ScopeRef::Dimension("/login")).is_some());
+        assert!(store.get("a", ScopeRef::Dimension("/login")).is_none());
+
+        assert!(store.remove("a", login).is_some());
+        assert_eq!(store.remove_identity("b"), 2);
+        assert!(store.id(ScopeRef::Route("/login")).is_some());
+        assert_eq!(store.retain(|_, id, _| id == ScopeId::Global), 1);
+        assert!(store.id(ScopeRef::Route("/login")).is_none());
+
+        store.get_or_insert("a", ScopeRef::Route("/other"), || RequestRecord::new(true));
+        assert_eq!(
+            store.id(ScopeRef::Route("/other")),
+            Some(ScopeId::Route(login_name(login))),
+            "released ids are reused"
+        );
+    }
+
+    fn recount(store: &Store<str>) -> usize {
+        let bytes: usize = store
+            .identities()
+            .map(|(who, scopes)| identity_size(who, scopes))
+            .sum();
+        bytes
+            + table_size::<(String, HashMap<ScopeId, RequestRecord>)>(store.records.capacity())
+            + store.names.memory_usage()
+            + store.queue_memory()
+    }
+
+    #[test]
+    fn test_memory_is_tracked_incrementally() {
+        let mut store: Store<str> = Store::new(usize::MAX);
+        for i in 0..50 {
+            let who = format!("client-{i}");
+            store.get_or_insert(&who, ScopeRef::Global, || RequestRecord::new(true));
+            let mut record = store.get_or_insert(&who, ScopeRef::Route("/search"), || {
+                RequestRecord::new(false)
+            });
+            for _ in 0..20 {
+                record.add_request(false, 600);
+            }
+        }
+        assert_eq!(store.memory_usage(), recount(&store));
+        assert!(store.memory_usage() > 50 * 20 * size_of::<u64>());
+        assert_eq!(
+            store.eviction_queue.len(),
+            50,
+            "identities are queued once, not per record"
+        );
+
+        store.remove_identity("client-0");
+        let search = store.id(ScopeRef::Route("/search")).unwrap();
+        store.remove("client-1", search);
+        store.retain(|who, _, _| who != "client-2");
+        assert_eq!(store.memory_usage(), recount(&store));
+
+        let before = store.memory_usage();
+        let removed = store.evict_to(before / 2, None);
+        assert!(removed > 0);
+        assert!(store.memory_usage() <= before / 2);
+        assert_eq!(store.memory_usage(), recount(&store));
+    }
+
+    #[test]
+    fn test_budget_is_enforced_on_insert() {
+        let mut store: Store<str> = Store::new(16 * 1024);
+        for i in 0..1000 {
+            store.get_or_insert(&format!("client-{i}"), ScopeRef::Route("/a"), || {
+                RequestRecord::new(true)
+            });
+            assert!(store.memory_usage() <= 16 * 1024);
+        }
+        assert!(store.evicted() > 0);
+        assert!(store.get("client-999", ScopeRef::Route("/a")).is_some());
+        assert!(
+            store.get("client-0", ScopeRef::Route("/a")).is_none(),
+            "ties are evicted in creation order"
+        );
+        assert_eq!(store.memory_usage(), recount(&store));
+    }
+
+    fn login_name(id: ScopeId) -> NameId {
+        match id {
+            ScopeId::Route(name) => name,
+            _ => unreachable!(),
+        }
+    }
+}
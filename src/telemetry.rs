@@ -0,0 +1,102 @@
+/* src/telemetry.rs */
+
+//! Optional metrics and tracing hooks. Every function here compiles to a no-op
+//! unless the matching cargo feature is enabled.
+
+#![cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+
+use std::fmt;
+
+/// Record the outcome of a limit check against the rule that decided it.
+pub(crate) fn record_decision<W: fmt::Display + ?Sized>(
+    who: &W,
+    route: &str,
+    rule: &str,
+    allowed: bool,
+) {
+    #[cfg(feature = "metrics")]
+    {
+        let name = if allowed {
+            "lazy_limit_requests_allowed_total"
+        } else {
+            "lazy_limit_requests_denied_total"
+        };
+        metrics::counter!(name, "rule" => rule.to_string()).increment(1);
+    }
+
+    // Denials are what people debug, so they get a cheaper-to-enable level than the hot path.
+    #[cfg(feature = "tracing")]
+    if allowed {
+        tracing::trace!(target: "lazy_limit::limiter", who = %who, route, rule, "request allowed");
+    } else {
+        tracing::debug!(target: "lazy_limit::limiter", who = %who, route, rule, "request denied");
+    }
+}
+
+/// Record a request that a shadow rule would have limited.
+pub(crate) fn record_shadow_violation<W: fmt::Display + ?Sized>(who: &W, route: &str, rule: &str) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("lazy_limit_shadow_violations_total", "rule" => rule.to_string())
+        .increment(1);
+
+    // Shadow rules exist to be watched before they are enforced, so log them visibly.
+    #[cfg(feature = "tracing")]
+    tracing::info!(target: "lazy_limit::limiter", who = %who, route, rule, "request would have been limited");
+}
+
+/// Record records evicted on insert to keep memory within `max_memory`.
+pub(crate) fn record_budget_eviction(removed: usize, memory_after: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!("lazy_limit_budget_evicted_records_total").increment(removed as u64);
+        metrics::gauge!("lazy_limit_memory_estimated_bytes").set(memory_after as f64);
+    }
+
+    // Evicting live records forgets counts early, which operators need to notice.
+    #[cfg(feature = "tracing")]
+    tracing::info!(
+        target: "lazy_limit::gc",
+        removed,
+        memory_after,
+        "evicted records to stay within max_memory"
+    );
+}
+
+/// Record a finished garbage collection pass.
+pub(crate) fn record_gc_run(
+    mode: &'static str,
+    removed: usize,
+    evicted: usize,
+    memory_before: usize,
+    memory_after: usize,
+) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!("lazy_limit_gc_runs_total", "mode" => mode).increment(1);
+        metrics::counter!("lazy_limit_gc_evicted_records_total").increment(evicted as u64);
+        metrics::gauge!("lazy_limit_memory_estimated_bytes").set(memory_after as f64);
+    }
+
+    #[cfg(feature = "tracing")]
+    if mode == "aggressive" {
+        tracing::info!(
+            target: "lazy_limit::gc",
+            mode,
+            removed,
+            evicted,
+            memory_before,
+            memory_after,
+            "garbage collection finished"
+        );
+    } else {
+        tracing::debug!(
+            target: "lazy_limit::gc",
+            mode,
+            removed,
+            evicted,
+            memory_before,
+            memory_after,
+            "garbage collection finished"
+        );
+    }
+}
@@ -0,0 +1,49 @@
+/* src/tier.rs */
+
+use crate::types::RuleConfig;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// A named plan with its own default and route rules, e.g. "free" or "pro".
+#[derive(Debug, Clone)]
+pub struct TierConfig {
+    pub default_rule: RuleConfig,
+    pub route_rules: HashMap<String, RuleConfig>,
+}
+
+impl TierConfig {
+    pub fn new(default_rule: RuleConfig) -> Self {
+        Self {
+            default_rule,
+            route_rules: HashMap::new(),
+        }
+    }
+
+    pub fn add_route_rule(mut self, route: &str, rule: RuleConfig) -> Self {
+        self.route_rules.insert(route.to_string(), rule);
+        self
+    }
+}
+
+type ResolveFn = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+/// Maps an identity to the name of its tier, or `None` to use the base rules.
+#[derive(Clone)]
+pub struct TierResolver(Arc<ResolveFn>);
+
+impl TierResolver {
+    pub fn new(resolve: impl Fn(&str) -> Option<String> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(resolve))
+    }
+
+    pub fn resolve(&self, who: &str) -> Option<String> {
+        (self.0)(who)
+    }
+}
+
+impl fmt::Debug for TierResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TierResolver(..)")
+    }
+}
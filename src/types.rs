@@ -1,5 +1,11 @@
 /* src/types.rs */
 
+use crate::access::IpAggregation;
+use crate::key::LimitKey;
+use crate::store::allocation_size;
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -42,19 +48,251 @@ impl Duration {
 pub struct RuleConfig {
     pub interval: Duration,
     pub limit: u32,
+    pub penalty: Option<Penalty>,
+    pub ip_aggregation: Option<IpAggregation>,
+    pub count_mode: CountMode,
+    /// Report violations without rejecting the request.
+    pub shadow: bool,
+}
+
+/// Which events a rule counts against its limit.
+#[derive(Debug, Clone, Default)]
+pub enum CountMode {
+    /// Every admitted request.
+    #[default]
+    AllRequests,
+    /// Only events reported through `record_failure`, e.g. failed logins.
+    /// Checks against the rule never consume quota.
+    FailuresOnly,
+    /// Only responses reported through `record_response` whose status matches
+    /// the filter. Checks against the rule never consume quota.
+    Responses(ResponseFilter),
+}
+
+type StatusFn = dyn Fn(u16) -> bool + Send + Sync;
+
+/// Selects the response status codes a rule counts, e.g. 4xx for scrapers.
+#[derive(Clone)]
+pub struct ResponseFilter(Arc<StatusFn>);
+
+impl ResponseFilter {
+    pub fn new(matches: impl Fn(u16) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(matches))
+    }
+
+    /// Statuses 400-499.
+    pub fn client_errors() -> Self {
+        Self::new(|status| (400..500).contains(&status))
+    }
+
+    /// Statuses 500-599.
+    pub fn server_errors() -> Self {
+        Self::new(|status| (500..600).contains(&status))
+    }
+
+    /// Statuses 400-599.
+    pub fn errors() -> Self {
+        Self::new(|status| (400..600).contains(&status))
+    }
+
+    pub fn matches(&self, status: u16) -> bool {
+        (self.0)(status)
+    }
+}
+
+impl fmt::Debug for ResponseFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ResponseFilter(..)")
+    }
 }
 
 impl RuleConfig {
     pub fn new(interval: Duration, limit: u32) -> Self {
-        Self { interval, limit }
+        Self {
+            interval,
+            limit,
+            penalty: None,
+            ip_aggregation: None,
+            count_mode: CountMode::AllRequests,
+            shadow: false,
+        }
+    }
+
+    /// Block repeat offenders for exponentially longer on each violation.
+    pub fn with_penalty(mut self, penalty: Penalty) -> Self {
+        self.penalty = Some(penalty);
+        self
+    }
+
+    /// Dry-run the rule: requests over the limit are still allowed, but come
+    /// back as `Decision::Shadowed` and are logged and counted in metrics.
+    pub fn shadow(mut self) -> Self {
+        self.shadow = true;
+        self
+    }
+
+    /// Only count failures reported through `record_failure` (login brute-force mode).
+    pub fn failures_only(mut self) -> Self {
+        self.count_mode = CountMode::FailuresOnly;
+        self
+    }
+
+    /// Only count responses whose status matches `filter`, reported through `record_response`.
+    pub fn count_responses(mut self, filter: ResponseFilter) -> Self {
+        self.count_mode = CountMode::Responses(filter);
+        self
+    }
+
+    pub fn counts_requests(&self) -> bool {
+        matches!(self.count_mode, CountMode::AllRequests)
+    }
+
+    /// Count IP identities per network instead of per address.
+    pub fn with_ip_aggregation(mut self, aggregation: IpAggregation) -> Self {
+        self.ip_aggregation = Some(aggregation);
+        self
+    }
+
+    /// The key this rule tracks `who` under.
+    pub fn normalize_key<'a, K: ?Sized + LimitKey>(&self, who: &'a K) -> Cow<'a, K> {
+        match &self.ip_aggregation {
+            Some(aggregation) => who.aggregate(aggregation),
+            None => Cow::Borrowed(who),
+        }
+    }
+}
+
+/// Exponential backoff for clients that keep exceeding a rule.
+///
+/// The first violation blocks for `base`, each consecutive one doubles the
+/// block up to `max`. The violation streak resets once a client goes
+/// `decay_after` without exceeding the rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Penalty {
+    pub base: Duration,
+    pub max: Duration,
+    pub decay_after: Duration,
+}
+
+impl Penalty {
+    pub fn new(base: Duration, max: Duration, decay_after: Duration) -> Self {
+        Self {
+            base,
+            max,
+            decay_after,
+        }
+    }
+
+    /// Block length in seconds for the given (1-based) consecutive violation.
+    pub fn block_seconds(&self, violations: u32) -> u64 {
+        let factor = 1u64
+            .checked_shl(violations.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        self.base
+            .as_seconds()
+            .saturating_mul(factor)
+            .min(self.max.as_seconds())
+    }
+}
+
+/// Outcome of a limit check and the reason for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// Every applicable rule had quota left.
+    Allowed,
+    /// The identity is allowlisted and bypassed all rules.
+    Allowlisted,
+    /// A rule's quota is used up. `rule` is the route whose rule matched,
+    /// `"default"`, or `"__global__"` for the global limit. `retry_after` is
+    /// the number of seconds until the rule allows requests again.
+    Limited { rule: String, retry_after: u64 },
+    /// A shadow rule would have limited the request, but it was allowed.
+    /// Fields are as for `Limited`.
+    Shadowed { rule: String, retry_after: u64 },
+    /// The identity already has `max_in_flight` requests running on `rule`'s route.
+    ConcurrencyLimited { rule: String, max_in_flight: u32 },
+    /// Too many callers are already waiting for this key in `until_ready`.
+    QueueFull { max_queue_len: usize },
+    /// `max_wait` seconds ran out while waiting behind earlier callers in the
+    /// `until_ready` queue.
+    QueueTimeout { max_wait: u64 },
+    /// The route is configured with `RouteMode::Deny`.
+    RouteDenied,
+    /// The identity is denylisted.
+    Denylisted,
+    /// The identity is banned until the given unix timestamp.
+    Banned { until: u64 },
+}
+
+impl Decision {
+    pub fn is_allowed(&self) -> bool {
+        matches!(
+            self,
+            Decision::Allowed | Decision::Allowlisted | Decision::Shadowed { .. }
+        )
+    }
+}
+
+/// The namespace a record is counted in. Keeping them apart means no `who`
+/// or route supplied by a caller can alias the global or dimension records.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Scope {
+    /// The global limit across every route.
+    Global,
+    /// A route's rule.
+    Route(String),
+    /// A composite dimension rule, by name.
+    Dimension(String),
+}
+
+impl Scope {
+    pub(crate) fn as_scope_ref(&self) -> ScopeRef<'_> {
+        match self {
+            Scope::Global => ScopeRef::Global,
+            Scope::Route(name) => ScopeRef::Route(name),
+            Scope::Dimension(name) => ScopeRef::Dimension(name),
+        }
     }
 }
 
+/// A borrowed `Scope`, used to look records up without allocating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ScopeRef<'a> {
+    Global,
+    Route(&'a str),
+    Dimension(&'a str),
+}
+
+impl ScopeRef<'_> {
+    pub fn to_scope(self) -> Scope {
+        match self {
+            ScopeRef::Global => Scope::Global,
+            ScopeRef::Route(name) => Scope::Route(name.to_string()),
+            ScopeRef::Dimension(name) => Scope::Dimension(name.to_string()),
+        }
+    }
+}
+
+/// Where a counted request landed in a `RequestRecord`, so it can be refunded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargeMark {
+    /// Counted in the fixed window starting at this timestamp.
+    Window(u64),
+    /// Stored as this entry in the sliding-window timestamps.
+    Timestamp(u64),
+}
+
 #[derive(Debug, Clone)]
 pub struct RequestRecord {
     pub count: u32,
     pub window_start: u64,
     pub timestamps: Vec<u64>,
+    /// Consecutive violations of a rule with a `Penalty`.
+    pub violations: u32,
+    pub last_violation: u64,
+    pub blocked_until: u64,
+    /// When the violation streak is forgotten; the record is kept until then.
+    pub streak_decays_at: u64,
 }
 
 impl RequestRecord {
@@ -67,10 +305,14 @@ impl RequestRecord {
             } else {
                 Vec::with_capacity(16)
             },
+            violations: 0,
+            last_violation: 0,
+            blocked_until: 0,
+            streak_decays_at: 0,
         }
     }
 
-    pub fn add_request(&mut self, is_short_interval: bool, window_size: u64) {
+    pub fn add_request(&mut self, is_short_interval: bool, window_size: u64) -> ChargeMark {
         let now = current_timestamp();
 
         if is_short_interval {
@@ -80,41 +322,112 @@ impl RequestRecord {
             } else {
                 self.count += 1;
             }
+            ChargeMark::Window(self.window_start)
         } else {
             self.timestamps.push(now);
             let cutoff = now.saturating_sub(window_size);
             self.timestamps.retain(|&t| t > cutoff);
             self.count = self.timestamps.len() as u32;
+            ChargeMark::Timestamp(now)
+        }
+    }
+
+    /// Undo the request `mark` was returned for. Returns whether it was still counted.
+    pub fn refund(&mut self, mark: ChargeMark) -> bool {
+        match mark {
+            ChargeMark::Window(window_start) => {
+                if self.window_start != window_start || self.count == 0 {
+                    return false;
+                }
+                self.count -= 1;
+            }
+            ChargeMark::Timestamp(timestamp) => {
+                let Some(pos) = self.timestamps.iter().rposition(|&t| t == timestamp) else {
+                    return false;
+                };
+                self.timestamps.remove(pos);
+                self.count = self.timestamps.len() as u32;
+            }
         }
+        true
     }
 
     pub fn is_limit_exceeded(&self, limit: u32, is_short_interval: bool, window_size: u64) -> bool {
+        self.current_count(is_short_interval, window_size) >= limit
+    }
+
+    /// Number of requests counted against the window that is active right now.
+    pub fn current_count(&self, is_short_interval: bool, window_size: u64) -> u32 {
         let now = current_timestamp();
         if is_short_interval {
             if now.saturating_sub(self.window_start) >= window_size {
-                false
+                0
             } else {
-                self.count >= limit
+                self.count
             }
         } else {
             let cutoff = now.saturating_sub(window_size);
-            let valid_requests = self.timestamps.iter().filter(|&&t| t > cutoff).count() as u32;
-            valid_requests >= limit
+            self.timestamps.iter().filter(|&&t| t > cutoff).count() as u32
         }
     }
 
+    /// Seconds until the current window, or an active penalty, lets a request through.
+    pub fn retry_after(&self, is_short_interval: bool, window_size: u64) -> u64 {
+        let now = current_timestamp();
+        let window_end = if is_short_interval {
+            self.window_start + window_size
+        } else {
+            let cutoff = now.saturating_sub(window_size);
+            self.timestamps
+                .iter()
+                .find(|&&t| t > cutoff)
+                .map_or(now, |&oldest| oldest + window_size)
+        };
+        window_end.max(self.blocked_until).saturating_sub(now)
+    }
+
+    pub fn is_penalized(&self) -> bool {
+        self.blocked_until > current_timestamp()
+    }
+
+    /// Extend the block for another violation and return its length in seconds.
+    /// Requests made while already blocked do not escalate the penalty further.
+    pub fn register_violation(&mut self, penalty: &Penalty) -> u64 {
+        let now = current_timestamp();
+        if self.blocked_until > now {
+            return self.blocked_until - now;
+        }
+        if now.saturating_sub(self.last_violation) >= penalty.decay_after.as_seconds() {
+            self.violations = 0;
+        }
+
+        self.violations = self.violations.saturating_add(1);
+        self.last_violation = now;
+        self.streak_decays_at = now + penalty.decay_after.as_seconds();
+        let block = penalty.block_seconds(self.violations);
+        self.blocked_until = now + block;
+        block
+    }
+
+    /// Bytes held by the record, including allocator rounding of its timestamps.
     pub fn memory_usage(&self) -> usize {
-        std::mem::size_of::<Self>() + self.timestamps.capacity() * std::mem::size_of::<u64>()
+        std::mem::size_of::<Self>() + self.heap_usage()
+    }
+
+    pub(crate) fn heap_usage(&self) -> usize {
+        allocation_size(self.timestamps.capacity() * std::mem::size_of::<u64>())
+    }
+
+    /// The latest request time the record knows of.
+    pub(crate) fn last_activity(&self) -> u64 {
+        *self.timestamps.last().unwrap_or(&self.window_start)
     }
 
     pub fn should_cleanup(&self, max_age_seconds: u64) -> bool {
         let now = current_timestamp();
-        let last_activity = if !self.timestamps.is_empty() {
-            *self.timestamps.last().unwrap_or(&self.window_start)
-        } else {
-            self.window_start
-        };
-        now.saturating_sub(last_activity) > max_age_seconds
+        now.saturating_sub(self.last_activity()) > max_age_seconds
+            && self.blocked_until <= now
+            && self.streak_decays_at <= now
     }
 }
 